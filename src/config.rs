@@ -1,16 +1,30 @@
-use alloy::primitives::Address;
+use alloy::{
+    dyn_abi::DynSolType,
+    json_abi::{Event, Function, JsonAbi, Param},
+    primitives::{keccak256, Address, B256},
+};
 //use reth_primitives::Address;
-use serde::Deserialize;
-use std::path::PathBuf;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::enrich::CallCache;
+use crate::filter::{deserialize_regex_option, preimage_hashes, topic_values, InputFilter};
 
 /// Represents an input parameter in the ABI.
 #[derive(Debug, Deserialize, Clone)]
 pub struct ABIInput {
     /// Indicates if the input parameter is indexed.
+    /// Not present on the components of a tuple.
+    #[serde(default)]
     pub indexed: bool,
 
     /// The internal type of the input parameter.
     #[serde(rename = "internalType")]
+    #[serde(default)]
     pub internal_type: String,
 
     /// The name of the input parameter.
@@ -20,11 +34,71 @@ pub struct ABIInput {
     #[serde(rename = "type")]
     pub type_: String,
 
+    /// Only index logs whose value of this input matches the regex.
     #[serde(
-        // deserialize_with = "deserialize_regex_option",
+        default,
+        deserialize_with = "deserialize_regex_option",
         rename = "rethRegexMatch"
     )]
-    pub regex: Option<String>,
+    pub regex: Option<Regex>,
+
+    /// Only index logs whose value of this input matches the predicates.
+    #[serde(rename = "rethFilter")]
+    pub filter: Option<InputFilter>,
+
+    /// The fields of a tuple (struct) parameter.
+    pub components: Option<Vec<ABIInput>>,
+
+    /// Store fixed-size `bytesN` values as BSON binary instead of hex strings.
+    #[serde(rename = "storeAsBinary")]
+    #[serde(default = "default_false")]
+    pub store_as_binary: bool,
+
+    /// Known values of an indexed `string` or `bytes` input, keyed by their keccak256 hash.
+    /// Topics only contain the hash of such values, matching ones are resolved back.
+    #[serde(rename = "knownPreimages")]
+    #[serde(default, deserialize_with = "deserialize_preimages")]
+    pub known_preimages: HashMap<B256, String>,
+}
+
+impl ABIInput {
+    pub fn from_param((position, param): (usize, Param)) -> Self {
+        let components: Vec<ABIInput> = param
+            .components
+            .into_iter()
+            .enumerate()
+            .map(ABIInput::from_param)
+            .collect();
+        Self {
+            indexed: false,
+            internal_type: param
+                .internal_type
+                .map(|t| t.to_string())
+                .unwrap_or_default(),
+            name: param_name(param.name, position),
+            type_: normalize_type(param.ty),
+            regex: None,
+            filter: None,
+            components: (!components.is_empty()).then_some(components),
+            store_as_binary: false,
+            known_preimages: HashMap::new(),
+        }
+    }
+
+    /// The type as used in the canonical event signature,
+    /// with tuples expanded to their components, e.g. `(address,uint24)[]`.
+    pub fn canonical_type(&self) -> String {
+        match (self.type_.strip_prefix("tuple"), &self.components) {
+            (Some(array_suffix), Some(components)) => {
+                let component_types: Vec<String> = components
+                    .iter()
+                    .map(|component| component.canonical_type())
+                    .collect();
+                format!("({}){}", component_types.join(","), array_suffix)
+            }
+            _ => self.type_.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -52,6 +126,156 @@ pub struct ABIItem {
     /// Apply custom indexes to the database
     #[serde(rename = "customDbIndexes")]
     pub custom_db_indexes: Option<Vec<Vec<CustomDbIndex>>>,
+
+    /// Anonymous events do not store their signature hash in the first topic.
+    /// They are matched by emitting address and topic count instead,
+    /// so the mapping has to filter by contract address.
+    #[serde(default = "default_false")]
+    pub anonymous: bool,
+
+    /// What to do with logs that match the ABI item but can not be decoded.
+    #[serde(rename = "onDecodeError")]
+    #[serde(default)]
+    pub on_decode_error: DecodeErrorPolicy,
+
+    /// View calls whose results are added to the documents, e.g. the `symbol()` of a token.
+    #[serde(rename = "enrichWithCalls")]
+    #[serde(default)]
+    pub enrich_with_calls: Vec<EnrichCall>,
+
+    /// The allowed topics of every indexed input, `None` if the input is not filtered.
+    /// Derived from the `equals` and `in` values of the `rethFilter` of the inputs.
+    #[serde(skip)]
+    pub topic_filters: Vec<Option<Vec<B256>>>,
+}
+
+impl ABIItem {
+    fn from_event(event: Event, collection_name: String) -> Self {
+        let inputs = event
+            .inputs
+            .into_iter()
+            .enumerate()
+            .map(|(position, param)| {
+                let indexed = param.indexed;
+                let param = Param {
+                    ty: param.ty,
+                    name: param.name,
+                    components: param.components,
+                    internal_type: param.internal_type,
+                };
+                ABIInput {
+                    indexed,
+                    ..ABIInput::from_param((position, param))
+                }
+            })
+            .collect();
+
+        Self {
+            inputs,
+            name: event.name,
+            collection_name,
+            custom_db_indexes: None,
+            anonymous: event.anonymous,
+            on_decode_error: DecodeErrorPolicy::default(),
+            enrich_with_calls: Vec::new(),
+            topic_filters: Vec::new(),
+        }
+    }
+
+    /// The keccak256 hash of the canonical event signature, stored in the first topic.
+    pub fn topic_id(&self) -> B256 {
+        let input_types: Vec<String> = self
+            .inputs
+            .iter()
+            .map(|input| input.canonical_type())
+            .collect();
+
+        keccak256(format!("{}({})", self.name, input_types.join(",")))
+    }
+
+    /// The first 4 bytes of the signature hash, which start the calldata of a function call.
+    pub fn selector(&self) -> [u8; 4] {
+        let mut selector = [0; 4];
+        selector.copy_from_slice(&self.topic_id()[..4]);
+        selector
+    }
+
+    /// Checks the topics of a log against the filtered values of the indexed inputs.
+    /// Topics missing from the log are left to the decoder to report.
+    pub fn matches_topic_filters(&self, topics: &[B256]) -> bool {
+        let signature_topics = if self.anonymous { 0 } else { 1 };
+        self.topic_filters.iter().enumerate().all(|(i, allowed)| {
+            match (allowed, topics.get(signature_topics + i)) {
+                (Some(allowed), Some(topic)) => allowed.contains(topic),
+                _ => true,
+            }
+        })
+    }
+
+    /// Derives the `topic_filters` from the `rethFilter` of the indexed inputs.
+    pub fn resolve_topic_filters(&mut self) -> Result<(), String> {
+        self.topic_filters = self
+            .inputs
+            .iter()
+            .filter(|input| input.indexed)
+            .map(|input| {
+                let Some(filter) = &input.filter else {
+                    return Ok(None);
+                };
+                let sol_type = DynSolType::parse(&input.canonical_type())
+                    .map_err(|e| format!("{}.{}: {}", self.name, input.name, e))?;
+                topic_values(&sol_type, filter)
+                    .map_err(|e| format!("{}.{}: {}", self.name, input.name, e))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    /// The number of topics of a log emitted by this event.
+    pub fn topic_count(&self) -> usize {
+        let indexed_inputs = self.inputs.iter().filter(|input| input.indexed).count();
+        if self.anonymous {
+            indexed_inputs
+        } else {
+            indexed_inputs + 1
+        }
+    }
+}
+
+/// A view call whose result is added to a document. It is executed against the state
+/// after the block of the document.
+#[derive(Debug, Deserialize, Clone)]
+pub struct EnrichCall {
+    /// Human-readable signature of a function without arguments,
+    /// e.g. `function symbol() returns (string)`.
+    #[serde(deserialize_with = "deserialize_function")]
+    pub function: Function,
+
+    /// The document field holding the address to call.
+    #[serde(rename = "addressField")]
+    #[serde(default = "default_address_field")]
+    pub address_field: String,
+
+    /// The document field to store the result in.
+    pub field: String,
+
+    /// Results are cached by address and selector, which suits constants like `decimals()`.
+    /// Disable it for values that change, like reserves.
+    #[serde(default = "default_true")]
+    pub cache: bool,
+}
+
+/// What to do with a log that can not be decoded.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DecodeErrorPolicy {
+    /// Log a warning and continue.
+    #[default]
+    Skip,
+    /// Stop the sync with an error.
+    Fail,
+    /// Store the raw log in the dead-letter collection and continue.
+    Store,
 }
 
 /// Represents a contract mapping in the Indexer.
@@ -62,19 +286,351 @@ pub struct IndexerContractMapping {
     // pub contract_address: Option<Address>,
     pub filter_by_contract_addresses: Option<Vec<Address>>,
 
-    /// How often you should sync back to the postgres db.
-    // #[serde(rename = "syncBackRoughlyEveryNLogs")]
-    // pub sync_back_every_n_log: u64,
+    /// How many decoded logs of a collection are buffered before they are written to MongoDB.
+    #[serde(rename = "syncBackRoughlyEveryNLogs")]
+    #[serde(default = "default_sync_back_every_n_log")]
+    pub sync_back_every_n_log: usize,
 
     /// The list of ABI items to decode.
+    /// Events from `abiFile` and `eventSignatures` are added to it when the config is loaded.
     #[serde(rename = "decodeAbiItems")]
+    #[serde(default)]
     pub decode_abi_items: Vec<ABIItem>,
+
+    /// Path to a compiler or Etherscan ABI JSON file, relative to the config file.
+    #[serde(rename = "abiFile")]
+    pub abi_file: Option<PathBuf>,
+
+    /// The events to pick from `abiFile`, all events if not set.
+    #[serde(rename = "abiEvents")]
+    pub abi_events: Option<Vec<String>>,
+
+    /// Human-readable event signatures,
+    /// e.g. `event Swap(address indexed sender, uint amount0In, uint amount1In)`.
+    #[serde(rename = "eventSignatures")]
+    #[serde(default)]
+    pub event_signatures: Vec<String>,
+
+    /// Collection name of the events from `abiFile` and `eventSignatures`,
+    /// `{event}` is replaced by the event name.
+    #[serde(rename = "collectionNameTemplate")]
+    #[serde(default = "default_collection_name_template")]
+    pub collection_name_template: String,
+
+    /// Also index the contracts created by a factory, e.g. the pools of a Uniswap factory.
+    #[serde(rename = "discoverAddressesFrom")]
+    pub discover_addresses_from: Option<AddressDiscovery>,
+
+    /// Index the plain ETH transfers of transactions, which emit no logs.
+    #[serde(rename = "nativeTransfers")]
+    pub native_transfers: Option<NativeTransfers>,
+
+    /// Index the contracts deployed by transactions, which need no factory events.
+    #[serde(rename = "contractDeployments")]
+    pub contract_deployments: Option<ContractDeployments>,
+
+    /// Track balance, nonce and storage changes of accounts, which need no events.
+    #[serde(rename = "stateChanges")]
+    pub state_changes: Option<StateChanges>,
+
+    /// Functions whose calls to the indexed contracts are decoded from the transaction input.
+    /// Only top-level calls are seen, not calls made by other contracts.
+    #[serde(rename = "decodeFunctionItems")]
+    #[serde(default)]
+    pub decode_function_items: Vec<ABIItem>,
+
+    /// Transaction fields stored on every event and call document, none by default.
+    #[serde(rename = "includeTxFields")]
+    #[serde(default)]
+    pub include_tx_fields: Vec<TxField>,
+
+    /// Results of the `enrichWithCalls` of the ABI items, shared by the workers.
+    #[serde(skip)]
+    pub call_cache: Arc<CallCache>,
+}
+
+/// An opt-in field of the transaction that emitted a log.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxField {
+    /// The sender, recovered from the signature.
+    From,
+    /// The recipient, `null` for contract creations.
+    To,
+    Nonce,
+    /// The gas price, or the max fee per gas of EIP-1559 transactions.
+    GasPrice,
+    /// The price per gas actually paid, including the priority fee.
+    EffectiveGasPrice,
+    GasUsed,
+    /// Whether the transaction succeeded.
+    Status,
+    /// The index of the log within the transaction.
+    TxLogIndex,
+}
+
+impl TxField {
+    /// The document field holding the value. Prefixed with `tx_`,
+    /// so a decoded input of the same name, like `to` or `status`, is not overwritten.
+    pub fn key(&self) -> &'static str {
+        match self {
+            TxField::From => "tx_from",
+            TxField::To => "tx_to",
+            TxField::Nonce => "tx_nonce",
+            TxField::GasPrice => "tx_gas_price",
+            TxField::EffectiveGasPrice => "tx_effective_gas_price",
+            TxField::GasUsed => "tx_gas_used",
+            TxField::Status => "tx_status",
+            TxField::TxLogIndex => "tx_log_index",
+        }
+    }
+}
+
+/// Indexes every transaction that sends ETH, optionally limited to some senders or recipients.
+#[derive(Debug, Deserialize, Clone)]
+pub struct NativeTransfers {
+    /// The name of the collection to store the transfers in.
+    #[serde(rename = "collectionName")]
+    pub collection_name: String,
+
+    /// Only index transfers sent by one of these addresses.
+    #[serde(rename = "fromAddresses")]
+    pub from_addresses: Option<Vec<Address>>,
+
+    /// Only index transfers to one of these addresses.
+    #[serde(rename = "toAddresses")]
+    pub to_addresses: Option<Vec<Address>>,
+}
+
+/// Indexes every contract deployed by a contract creation transaction.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ContractDeployments {
+    /// The name of the collection to store the deployments in.
+    #[serde(rename = "collectionName")]
+    pub collection_name: String,
+
+    /// Only index contracts deployed by one of these addresses.
+    pub deployers: Option<Vec<Address>>,
+
+    /// Runtime bytecode hashes to compare the deployed code with, e.g. of a known token.
+    #[serde(rename = "codeHashes")]
+    pub code_hashes: Option<Vec<B256>>,
+}
+
+/// Reads the account and storage changesets of every block for the configured accounts.
+#[derive(Debug, Deserialize, Clone)]
+pub struct StateChanges {
+    /// The name of the collection to store the changes in.
+    #[serde(rename = "collectionName")]
+    pub collection_name: String,
+
+    /// The accounts whose balance and nonce changes are indexed.
+    pub addresses: Vec<Address>,
+
+    /// Storage slots of the accounts to index as well, e.g. the EIP-1967 implementation slot.
+    #[serde(rename = "storageSlots")]
+    pub storage_slots: Option<Vec<B256>>,
+}
+
+/// An address parameter of a factory event whose values are indexed by a dependent mapping
+/// from the block they were emitted in.
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq, Hash)]
+pub struct AddressDiscovery {
+    /// The collection of the factory event, indexed by another mapping.
+    #[serde(rename = "collectionName")]
+    pub collection_name: String,
+
+    /// The name of the `address` input holding the created contract.
+    pub parameter: String,
+}
+
+impl IndexerContractMapping {
+    /// Converts the events of `abiFile` and `eventSignatures` into ABI items.
+    fn resolve_abi_items(&mut self, config_dir: &Path) -> Result<(), String> {
+        let mut events = Vec::new();
+
+        if let Some(abi_file) = &self.abi_file {
+            let path = config_dir.join(abi_file);
+            let content = fs::read_to_string(&path)
+                .map_err(|e| format!("failed to read ABI file {:?}: {}", path, e))?;
+            let abi = parse_abi_json(&content)
+                .map_err(|e| format!("failed to parse ABI file {:?}: {}", path, e))?;
+
+            match &self.abi_events {
+                Some(names) => {
+                    for name in names {
+                        let overloads = abi
+                            .events
+                            .get(name)
+                            .ok_or_else(|| format!("event {} not found in {:?}", name, path))?;
+                        events.extend(overloads.iter().cloned());
+                    }
+                }
+                None => events.extend(abi.events().cloned()),
+            }
+        }
+
+        for signature in &self.event_signatures {
+            let event = Event::parse(signature)
+                .map_err(|e| format!("invalid event signature {:?}: {}", signature, e))?;
+            events.push(event);
+        }
+
+        for event in events {
+            let collection_name = self
+                .collection_name_template
+                .replace("{event}", &event.name);
+            self.decode_abi_items
+                .push(ABIItem::from_event(event, collection_name));
+        }
+
+        if self.decode_abi_items.is_empty()
+            && self.decode_function_items.is_empty()
+            && self.native_transfers.is_none()
+            && self.contract_deployments.is_none()
+            && self.state_changes.is_none()
+        {
+            return Err("a mapping needs decodeAbiItems, abiFile, eventSignatures, \
+                decodeFunctionItems, nativeTransfers, contractDeployments or stateChanges"
+                .to_string());
+        }
+
+        for abi_item in &mut self.decode_abi_items {
+            abi_item.resolve_topic_filters()?;
+        }
+        Ok(())
+    }
+
+    /// The key under which the sync checkpoint of this mapping is stored.
+    /// Derived from the collection names, so it stays stable between restarts.
+    pub fn checkpoint_id(&self) -> String {
+        let mut collections = self.collection_names();
+        collections.sort();
+        collections.join(",")
+    }
+
+    /// Every collection the mapping writes to.
+    pub fn collection_names(&self) -> Vec<&str> {
+        self.decode_abi_items
+            .iter()
+            .chain(&self.decode_function_items)
+            .map(|abi_item| abi_item.collection_name.as_str())
+            .chain(
+                self.native_transfers
+                    .iter()
+                    .map(|native_transfers| native_transfers.collection_name.as_str()),
+            )
+            .chain(
+                self.contract_deployments
+                    .iter()
+                    .map(|deployments| deployments.collection_name.as_str()),
+            )
+            .chain(
+                self.state_changes
+                    .iter()
+                    .map(|state_changes| state_changes.collection_name.as_str()),
+            )
+            .collect()
+    }
 }
 
 fn default_false() -> bool {
     false
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_address_field() -> String {
+    "contract_address".to_string()
+}
+
+fn deserialize_function<'de, D>(deserializer: D) -> Result<Function, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let signature = String::deserialize(deserializer)?;
+    Function::parse(&signature).map_err(serde::de::Error::custom)
+}
+
+/// Hashes every preimage as a string. Values that are valid hex are also hashed as raw bytes,
+/// so the same list works for `bytes` inputs.
+fn deserialize_preimages<'de, D>(deserializer: D) -> Result<HashMap<B256, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let preimages = Vec::<String>::deserialize(deserializer)?;
+
+    let mut hashes = HashMap::new();
+    for preimage in preimages {
+        for hash in preimage_hashes(&preimage) {
+            hashes.insert(hash, preimage.clone());
+        }
+    }
+    Ok(hashes)
+}
+
+/// Human-readable signatures may use aliases like `uint`, the event signature needs `uint256`.
+fn normalize_type(type_: String) -> String {
+    if type_.starts_with("tuple") {
+        return type_;
+    }
+    DynSolType::parse(&type_)
+        .map(|sol_type| sol_type.sol_type_name().into_owned())
+        .unwrap_or(type_)
+}
+
+/// Unnamed parameters are stored under their position, e.g. `arg0`.
+fn param_name(name: String, position: usize) -> String {
+    if name.is_empty() {
+        format!("arg{}", position)
+    } else {
+        name
+    }
+}
+
+/// Accepts a plain ABI array (Etherscan, `solc --abi`) or a compiler artifact with an `abi` field.
+fn parse_abi_json(content: &str) -> serde_json::Result<JsonAbi> {
+    let mut value: serde_json::Value = serde_json::from_str(content)?;
+    if let Some(abi) = value.get_mut("abi") {
+        value = abi.take();
+    }
+    serde_json::from_value(value)
+}
+
+fn default_chain() -> String {
+    "mainnet".to_string()
+}
+
+fn default_collection_name_template() -> String {
+    "{event}".to_string()
+}
+
+fn default_sync_back_every_n_log() -> usize {
+    1_000
+}
+
+fn default_flush_interval_ms() -> u64 {
+    5_000
+}
+
+fn default_poll_interval_ms() -> u64 {
+    2_000
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_chunk_size() -> u64 {
+    10_000
+}
+
+fn default_reorg_window() -> usize {
+    64
+}
+
 // /// Represents a contract mapping in the Indexer.
 // #[derive(Debug, Deserialize)]
 // pub struct IndexerPostgresConfig {
@@ -94,7 +650,7 @@ fn default_false() -> bool {
 // }
 
 /// Represents a contract mapping in the Indexer.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone)]
 pub struct IndexerMongoDBConfig {
     // /// If true, the tables will be dropped and recreated before syncing.
     // #[serde(rename = "dropTableBeforeSync")]
@@ -146,25 +702,99 @@ pub struct IndexerMongoDBConfig {
 //     pub data_directory: String,
 // }
 
-#[derive(Debug, Deserialize)]
+/// A reth database indexed next to the others of the same config,
+/// e.g. a mainnet and a sepolia node.
+#[derive(Debug, Deserialize, Clone)]
+pub struct IndexerSourceConfig {
+    /// Shown in the progress and logs, defaults to the chain.
+    pub name: Option<String>,
+
+    /// The location of the rethDB.
+    #[serde(rename = "rethDBLocation")]
+    pub reth_db_location: PathBuf,
+
+    /// The chain of the reth database, see `IndexerConfig::chain`.
+    #[serde(default = "default_chain")]
+    pub chain: String,
+
+    /// The starting block number.
+    #[serde(rename = "fromBlockNumber")]
+    pub from_block: u64,
+
+    /// The end block number (exclusive), the tip is followed if omitted.
+    #[serde(rename = "toBlockNumber")]
+    pub to_block: Option<u64>,
+
+    /// The MongoDB database to write to, `mongodb.database` if omitted.
+    pub database: Option<String>,
+
+    /// The list of contract mappings.
+    #[serde(rename = "eventMappings")]
+    #[serde(default)]
+    pub event_mappings: Vec<IndexerContractMapping>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
 pub struct IndexerConfig {
+    /// Shown in the progress and logs, defaults to the chain.
+    pub name: Option<String>,
+
     /// The location of the rethDB.
     #[serde(rename = "rethDBLocation")]
+    #[serde(default)]
     pub reth_db_location: PathBuf,
 
     /// The location of the CSV.
     // #[serde(rename = "csvLocation")]
     // pub csv_location: PathBuf,
 
+    /// The chain of the reth database: `mainnet`, `sepolia`, `holesky`, `dev`
+    /// or the path to the genesis JSON file of a custom Ethereum chain.
+    #[serde(default = "default_chain")]
+    pub chain: String,
+
     /// The starting block number.
     #[serde(rename = "fromBlockNumber")]
+    #[serde(default)]
     pub from_block: u64,
 
-    // pub to_block: Option<u64>,
-    /// The starting block number.
-    /// For now to_block is required
+    /// The end block number (exclusive).
+    /// If omitted, the indexer catches up to the tip and keeps following it.
     #[serde(rename = "toBlockNumber")]
-    pub to_block: u64,
+    pub to_block: Option<u64>,
+
+    /// How often the reth database is polled for new blocks when following the tip.
+    #[serde(rename = "pollIntervalMs")]
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+
+    /// How many blocks to stay behind the tip when following it.
+    #[serde(default)]
+    pub confirmations: u64,
+
+    /// The longest time buffered documents wait before they are written to MongoDB.
+    #[serde(rename = "flushIntervalMs")]
+    #[serde(default = "default_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+
+    /// How many workers process blocks in parallel during the historical sync.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+
+    /// How many consecutive blocks a worker takes at once.
+    #[serde(rename = "chunkSize")]
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u64,
+
+    /// How many recently indexed blocks are checked for reorgs when following the tip.
+    #[serde(rename = "reorgWindow")]
+    #[serde(default = "default_reorg_window")]
+    pub reorg_window: usize,
+
+    /// If true, stored checkpoints are ignored and the sync starts from `fromBlockNumber`.
+    #[serde(rename = "forceFreshSync")]
+    #[serde(default = "default_false")]
+    pub force_fresh_sync: bool,
 
     /// The mongodb configuration.
     pub mongodb: IndexerMongoDBConfig,
@@ -182,5 +812,371 @@ pub struct IndexerConfig {
     // pub parquet: Option<IndexerParquetConfig>,
     /// The list of contract mappings.
     #[serde(rename = "eventMappings")]
+    #[serde(default)]
     pub event_mappings: Vec<IndexerContractMapping>,
+
+    /// Several reth databases indexed concurrently, replacing `rethDBLocation`, `chain`,
+    /// the block range and `eventMappings`. The other settings are shared by all sources.
+    #[serde(default)]
+    pub sources: Vec<IndexerSourceConfig>,
+}
+
+impl IndexerConfig {
+    /// Splits the config into one config per source, each with its own reth database,
+    /// chain, block range and mappings. A config without `sources` is a single source.
+    pub fn into_sources(self) -> Vec<IndexerConfig> {
+        if self.sources.is_empty() {
+            return vec![self];
+        }
+
+        let shared = IndexerConfig {
+            sources: Vec::new(),
+            ..self
+        };
+        self.sources
+            .into_iter()
+            .map(|source| {
+                let mut mongodb = shared.mongodb.clone();
+                if let Some(database) = source.database {
+                    mongodb.database = database;
+                }
+                IndexerConfig {
+                    name: source.name,
+                    reth_db_location: source.reth_db_location,
+                    chain: source.chain,
+                    from_block: source.from_block,
+                    to_block: source.to_block,
+                    mongodb,
+                    event_mappings: source.event_mappings,
+                    ..shared.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Checks that sources of the same chain do not write the same collection of a database,
+    /// they would overwrite each other's checkpoints. Sources are paired with the chain id of
+    /// their chain spec, the same chain can be configured by name or by its genesis file.
+    pub fn validate_sources(sources: &[(&IndexerConfig, u64)]) -> Result<(), String> {
+        for (i, (config, chain_id)) in sources.iter().enumerate() {
+            for (other, other_chain_id) in &sources[i + 1..] {
+                if chain_id != other_chain_id || config.mongodb.database != other.mongodb.database {
+                    continue;
+                }
+                let collections = config.collection_names();
+                if let Some(collection_name) = other
+                    .collection_names()
+                    .into_iter()
+                    .find(|collection_name| collections.contains(collection_name))
+                {
+                    return Err(format!(
+                        "sources {} and {} both write {} of chain {} into {}",
+                        config.source_name(),
+                        other.source_name(),
+                        collection_name,
+                        chain_id,
+                        config.mongodb.database
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The name of the source in the progress and logs.
+    pub fn source_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.chain)
+    }
+
+    fn collection_names(&self) -> Vec<&str> {
+        self.event_mappings
+            .iter()
+            .flat_map(|mapping| mapping.collection_names())
+            .collect()
+    }
+
+    /// Converts the ABI files and event signatures of every mapping into ABI items
+    /// and derives the topic filters of their indexed inputs.
+    /// Relative paths are resolved from `config_dir`.
+    pub fn resolve_abi_items(&mut self, config_dir: &Path) -> Result<(), String> {
+        for mapping in &mut self.event_mappings {
+            mapping.resolve_abi_items(config_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Checks the parts of the configuration that can not be expressed by its types.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.reth_db_location.as_os_str().is_empty() {
+            return Err(format!("{} has no rethDBLocation", self.source_name()));
+        }
+        if self.event_mappings.is_empty() {
+            return Err(format!("{} has no eventMappings", self.source_name()));
+        }
+
+        for (i, mapping) in self.event_mappings.iter().enumerate() {
+            if let Some(discovery) = &mapping.discover_addresses_from {
+                // Blocks are processed mapping by mapping, so children created in a block
+                // are known before the logs of the same block are filtered by them
+                let factory_item = self.event_mappings[..i]
+                    .iter()
+                    .flat_map(|mapping| &mapping.decode_abi_items)
+                    .find(|abi_item| abi_item.collection_name == discovery.collection_name)
+                    .ok_or_else(|| {
+                        format!(
+                            "discoverAddressesFrom needs a mapping of {} listed before it",
+                            discovery.collection_name
+                        )
+                    })?;
+                if !factory_item.inputs.iter().any(|input| {
+                    input.name == discovery.parameter && input.canonical_type() == "address"
+                }) {
+                    return Err(format!(
+                        "{} has no address input {}",
+                        factory_item.name, discovery.parameter
+                    ));
+                }
+            }
+
+            for abi_item in mapping
+                .decode_abi_items
+                .iter()
+                .chain(&mapping.decode_function_items)
+            {
+                for call in &abi_item.enrich_with_calls {
+                    if !call.function.inputs.is_empty() || call.function.outputs.is_empty() {
+                        return Err(format!(
+                            "{} of {} needs outputs and no arguments",
+                            call.function.name, abi_item.name
+                        ));
+                    }
+                }
+            }
+
+            for function_item in &mapping.decode_function_items {
+                if function_item.inputs.iter().any(|input| input.indexed) {
+                    return Err(format!(
+                        "function {} can not have indexed inputs",
+                        function_item.name
+                    ));
+                }
+            }
+
+            for abi_item in &mapping.decode_abi_items {
+                let indexed_inputs = abi_item.inputs.iter().filter(|input| input.indexed).count();
+                let max_indexed_inputs = if abi_item.anonymous { 4 } else { 3 };
+                if indexed_inputs > max_indexed_inputs {
+                    return Err(format!(
+                        "{} has {} indexed inputs, at most {} are possible",
+                        abi_item.name, indexed_inputs, max_indexed_inputs
+                    ));
+                }
+
+                // Events sharing a signature are told apart by their topic count
+                if let Some(other) = mapping.decode_abi_items.iter().find(|other| {
+                    !std::ptr::eq(*other, abi_item)
+                        && !other.anonymous
+                        && !abi_item.anonymous
+                        && other.topic_id() == abi_item.topic_id()
+                        && other.topic_count() == abi_item.topic_count()
+                }) {
+                    return Err(format!(
+                        "{} ({}) and {} ({}) share a signature and indexed input count",
+                        abi_item.name, abi_item.collection_name, other.name, other.collection_name
+                    ));
+                }
+
+                if abi_item.anonymous
+                    && mapping.discover_addresses_from.is_none()
+                    && mapping
+                        .filter_by_contract_addresses
+                        .as_ref()
+                        .is_none_or(|addresses| addresses.is_empty())
+                {
+                    return Err(format!(
+                        "{} is anonymous, its mapping has to set filterByContractAddress or discoverAddressesFrom",
+                        abi_item.name
+                    ));
+                }
+
+                for input in &abi_item.inputs {
+                    // A type the decoder can not parse would fail every log or call of the item
+                    let sol_type = DynSolType::parse(&input.canonical_type()).map_err(|e| {
+                        format!(
+                            "{}.{} has an unsupported type {}: {}",
+                            abi_item.name, input.name, input.type_, e
+                        )
+                    })?;
+
+                    // Decoded arrays and tuples are no single value a filter could match,
+                    // indexed ones are matched by their hash
+                    let is_compound = matches!(
+                        sol_type,
+                        DynSolType::Array(_) | DynSolType::FixedArray(..) | DynSolType::Tuple(_)
+                    );
+                    if is_compound
+                        && !input.indexed
+                        && (input.regex.is_some() || input.filter.is_some())
+                    {
+                        return Err(format!(
+                            "{}.{} is an array or tuple, rethRegexMatch and rethFilter only apply to single values",
+                            abi_item.name, input.name
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn tuples_are_expanded_in_the_signature() {
+        let input: ABIInput = serde_json::from_value(json!({
+            "name": "fees",
+            "type": "tuple[]",
+            "components": [
+                { "name": "pool", "type": "address" },
+                {
+                    "name": "tiers",
+                    "type": "tuple[2]",
+                    "components": [
+                        { "name": "fee", "type": "uint24" },
+                        { "name": "enabled", "type": "bool" },
+                    ],
+                },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(input.canonical_type(), "(address,(uint24,bool)[2])[]");
+    }
+
+    fn mapping(mapping: serde_json::Value) -> IndexerContractMapping {
+        serde_json::from_value(mapping).unwrap()
+    }
+
+    #[test]
+    fn event_signatures_normalize_type_aliases() {
+        let mut swaps = mapping(json!({
+            "eventSignatures": [
+                "event Swap(address indexed sender, uint amount0In, int, (uint, bool) data)"
+            ],
+            "collectionNameTemplate": "uniswap_{event}",
+        }));
+        swaps.resolve_abi_items(Path::new(".")).unwrap();
+
+        let swap = &swaps.decode_abi_items[0];
+        assert_eq!(swap.collection_name, "uniswap_Swap");
+        let types: Vec<String> = swap.inputs.iter().map(|i| i.canonical_type()).collect();
+        assert_eq!(types, ["address", "uint256", "int256", "(uint256,bool)"]);
+        assert!(swap.inputs[0].indexed && !swap.inputs[1].indexed);
+        assert_eq!(swap.inputs[2].name, "arg2");
+        assert_eq!(
+            swap.topic_id(),
+            keccak256("Swap(address,uint256,int256,(uint256,bool))")
+        );
+    }
+
+    #[test]
+    fn selectors_are_the_first_bytes_of_the_signature_hash() {
+        let transfer: ABIItem = serde_json::from_value(json!({
+            "name": "transfer",
+            "collectionName": "transfer_calls",
+            "inputs": [
+                { "name": "to", "type": "address" },
+                { "name": "amount", "type": "uint256" },
+            ],
+        }))
+        .unwrap();
+
+        assert_eq!(transfer.selector(), [0xa9, 0x05, 0x9c, 0xbb]);
+    }
+
+    #[test]
+    fn abi_files_accept_artifacts_and_pick_abi_events() {
+        let dir = std::env::temp_dir().join(format!("reth-indexer-abi-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let artifact = json!({
+            "contractName": "Pair",
+            "abi": [
+                {
+                    "type": "event",
+                    "name": "Sync",
+                    "anonymous": false,
+                    "inputs": [
+                        { "name": "reserve0", "type": "uint112", "indexed": false },
+                        { "name": "reserve1", "type": "uint112", "indexed": false },
+                    ],
+                },
+                {
+                    "type": "event",
+                    "name": "Transfer",
+                    "anonymous": false,
+                    "inputs": [
+                        { "name": "from", "type": "address", "indexed": true },
+                        { "name": "to", "type": "address", "indexed": true },
+                        { "name": "value", "type": "uint256", "indexed": false },
+                    ],
+                },
+            ],
+        });
+        fs::write(dir.join("Pair.json"), artifact.to_string()).unwrap();
+
+        let mut pairs = mapping(json!({
+            "abiFile": "Pair.json",
+            "abiEvents": ["Sync"],
+        }));
+        pairs.resolve_abi_items(&dir).unwrap();
+        let names: Vec<&str> = pairs
+            .decode_abi_items
+            .iter()
+            .map(|abi_item| abi_item.name.as_str())
+            .collect();
+        assert_eq!(names, ["Sync"]);
+        assert_eq!(
+            pairs.decode_abi_items[0].topic_id(),
+            keccak256("Sync(uint112,uint112)")
+        );
+
+        let mut missing = mapping(json!({
+            "abiFile": "Pair.json",
+            "abiEvents": ["Swap"],
+        }));
+        assert!(missing.resolve_abi_items(&dir).is_err());
+
+        let mut all_events = mapping(json!({ "abiFile": "Pair.json" }));
+        all_events.resolve_abi_items(&dir).unwrap();
+        assert_eq!(all_events.decode_abi_items.len(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sources_of_the_same_chain_id_can_not_share_collections() {
+        let source = |chain: &str| {
+            let mut config: IndexerConfig = serde_json::from_value(json!({
+                "chain": chain,
+                "mongodb": { "connectionString": "mongodb://localhost", "database": "indexer" },
+                "eventMappings": [{
+                    "eventSignatures": ["event Sync(uint112 reserve0, uint112 reserve1)"],
+                }],
+            }))
+            .unwrap();
+            config.resolve_abi_items(Path::new(".")).unwrap();
+            config
+        };
+        let mainnet = source("mainnet");
+        let genesis = source("./mainnet-genesis.json");
+        let sepolia = source("sepolia");
+
+        assert!(IndexerConfig::validate_sources(&[(&mainnet, 1), (&genesis, 1)]).is_err());
+        assert!(IndexerConfig::validate_sources(&[(&mainnet, 1), (&sepolia, 11155111)]).is_ok());
+    }
 }
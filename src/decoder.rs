@@ -1,13 +1,15 @@
 use alloy::{
-    dyn_abi::SolType,
-    primitives::{Address, B256},
-    sol_types::sol_data::{self, IntBitCount, SupportedInt},
+    dyn_abi::{DynSolType, DynSolValue, FunctionExt},
+    json_abi::Function,
+    primitives::{hex, Address, B256},
 };
-use mongodb::bson::{Bson, Decimal128};
+use mongodb::bson::{spec::BinarySubtype, Binary, Bson, Decimal128, Document};
 use reth_primitives::Log;
+use std::fmt;
 use std::str::FromStr;
 
 use crate::config::{ABIInput, ABIItem};
+use crate::filter::regex_matches;
 
 #[derive(Debug)]
 pub struct DecodedTopic {
@@ -19,25 +21,108 @@ pub struct DecodedTopic {
 #[derive(Debug)]
 pub struct DecodedLog {
     pub address: Address,
+    /// Position of the log within the block.
+    pub log_index: u64,
     pub topics: Vec<DecodedTopic>,
 }
 
-pub fn decode_logs(topic_id: B256, logs: &[Log], abi_item: &ABIItem) -> Vec<DecodedLog> {
+/// Reasons a log can not be decoded with an ABI item.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The number of topics does not match the indexed inputs of the ABI item.
+    TopicCountMismatch { expected: usize, found: usize },
+    /// The ABI type of an input is not supported.
+    UnsupportedType(String),
+    /// The log data or calldata does not match the non-indexed inputs.
+    InvalidData(String),
+    /// The value of an input could not be decoded.
+    InvalidValue { input: String, reason: String },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TopicCountMismatch { expected, found } => {
+                write!(f, "expected {} indexed topics, found {}", expected, found)
+            }
+            DecodeError::UnsupportedType(type_) => write!(f, "unsupported type: {}", type_),
+            DecodeError::InvalidData(reason) => write!(f, "invalid data: {}", reason),
+            DecodeError::InvalidValue { input, reason } => {
+                write!(f, "invalid value for {}: {}", input, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A log that matched an ABI item but could not be decoded with it.
+#[derive(Debug)]
+pub struct DecodeFailure {
+    /// Position of the log within the block.
+    pub log_index: u64,
+    pub log: Log,
+    pub error: DecodeError,
+}
+
+/// Decodes the logs emitted by `abi_item`. Each log is paired with its index within the block.
+///
+/// Logs are matched by their signature topic, or for anonymous events by their topic count
+/// if their first topic is not the signature of another item.
+/// Events sharing a signature (e.g. the ERC-20 and ERC-721 `Transfer`) only differ in which
+/// inputs are indexed, so a log only goes to the variant whose topic count matches.
+/// Logs with the signature of `abi_item` but another topic count belong to a variant
+/// the mapping does not index and are skipped.
+/// Logs whose values do not match the `rethRegexMatch` or `rethFilter` of an input are dropped.
+pub fn decode_logs(
+    logs: &[(u64, Log)],
+    abi_item: &ABIItem,
+    abi_items: &[ABIItem],
+) -> Vec<Result<DecodedLog, DecodeFailure>> {
+    let topic_id = abi_item.topic_id();
+    // Logs of the regular events of the mapping are never anonymous events
+    let signature_topics: Vec<B256> = abi_items
+        .iter()
+        .filter(|other| !other.anonymous)
+        .map(|other| other.topic_id())
+        .collect();
+
     logs.iter()
-        .filter_map(|log| {
-            let topic = log.topics();
-            if topic.len() > 0 && topic[0] == topic_id {
-                decode_log(log, abi_item).ok()
-            } else {
-                None
+        .filter(|(_, log)| {
+            if abi_item.anonymous {
+                return log.topics().len() == abi_item.topic_count()
+                    && log
+                        .topics()
+                        .first()
+                        .is_none_or(|topic| !signature_topics.contains(topic));
             }
+            log.topics().first() == Some(&topic_id) && log.topics().len() == abi_item.topic_count()
+        })
+        .filter(|(_, log)| abi_item.matches_topic_filters(log.topics()))
+        .filter_map(|(log_index, log)| {
+            decode_log(log, *log_index, abi_item)
+                .map_err(|error| DecodeFailure {
+                    log_index: *log_index,
+                    log: log.clone(),
+                    error,
+                })
+                .transpose()
         })
         .collect()
 }
 
-fn decode_log(log: &Log, abi_item: &ABIItem) -> Result<DecodedLog, ()> {
-    let decoded_indexed_topics = decode_log_topics(log, abi_item)?;
-    let decoded_non_indexed_data = decode_log_data(log, abi_item)?;
+/// Returns `None` if the log does not match the filters of the inputs.
+fn decode_log(
+    log: &Log,
+    log_index: u64,
+    abi_item: &ABIItem,
+) -> Result<Option<DecodedLog>, DecodeError> {
+    let Some(decoded_indexed_topics) = decode_log_topics(log, abi_item)? else {
+        return Ok(None);
+    };
+    let Some(decoded_non_indexed_data) = decode_log_data(log, abi_item)? else {
+        return Ok(None);
+    };
 
     let mut topics: Vec<DecodedTopic> = decoded_indexed_topics
         .into_iter()
@@ -45,223 +130,767 @@ fn decode_log(log: &Log, abi_item: &ABIItem) -> Result<DecodedLog, ()> {
         .collect();
 
     topics.sort_by_key(|item| {
-        abi_item
-            .inputs
-            .iter()
-            .position(|input| input.name == item.name)
+        abi_item.inputs.iter().position(|input| {
+            input.name == item.name || item.name.strip_suffix("_hash") == Some(input.name.as_str())
+        })
     });
 
-    Ok(DecodedLog {
+    Ok(Some(DecodedLog {
         address: log.address,
+        log_index,
         topics,
-    })
+    }))
 }
 
-fn decode_log_topics(log: &Log, abi: &ABIItem) -> Result<Vec<DecodedTopic>, ()> {
+fn decode_log_topics(log: &Log, abi: &ABIItem) -> Result<Option<Vec<DecodedTopic>>, DecodeError> {
     let indexed_inputs: Vec<&ABIInput> = abi
         .inputs
         .iter()
         .filter(|input| input.indexed)
         .collect::<Vec<_>>();
 
-    if indexed_inputs.len() != log.topics().len() - 1 {
-        // -1 because the first topic is the event signature
-        return Err(());
+    // The first topic is the event signature, unless the event is anonymous
+    let signature_topics = if abi.anonymous { 0 } else { 1 };
+    let found = log.topics().len().saturating_sub(signature_topics);
+    if indexed_inputs.len() != found {
+        return Err(DecodeError::TopicCountMismatch {
+            expected: indexed_inputs.len(),
+            found,
+        });
     }
 
     let mut results: Vec<DecodedTopic> = Vec::<DecodedTopic>::new();
 
-    for (i, topic) in log.topics().iter().enumerate().skip(1) {
-        let abi_input = indexed_inputs[i - 1];
-        results.push(decode_topic_log(topic.as_slice(), abi_input)?);
+    for (topic, abi_input) in log
+        .topics()
+        .iter()
+        .skip(signature_topics)
+        .zip(indexed_inputs)
+    {
+        let sol_type = parse_type(abi_input)?;
+        if is_hashed_in_topic(&sol_type) {
+            if !hashed_input_matches(*topic, abi_input) {
+                return Ok(None);
+            }
+            results.extend(decode_hashed_topic(*topic, abi_input));
+            continue;
+        }
+
+        let value =
+            sol_type
+                .abi_decode(topic.as_slice())
+                .map_err(|e| DecodeError::InvalidValue {
+                    input: abi_input.name.clone(),
+                    reason: e.to_string(),
+                })?;
+        if !input_matches(&value, abi_input) {
+            return Ok(None);
+        }
+        results.push(DecodedTopic {
+            name: abi_input.name.clone(),
+            value: dyn_value_to_bson(value, abi_input)?,
+        });
     }
 
-    Ok(results)
+    Ok(Some(results))
+}
+
+fn input_matches(value: &DynSolValue, abi_input: &ABIInput) -> bool {
+    abi_input
+        .regex
+        .as_ref()
+        .is_none_or(|regex| regex_matches(regex, value))
+        && abi_input
+            .filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches(value))
+}
+
+/// Only the hash of an indexed dynamic value is known, so a regex can only match a known preimage.
+fn hashed_input_matches(topic: B256, abi_input: &ABIInput) -> bool {
+    let preimage_matches = abi_input.regex.as_ref().is_none_or(|regex| {
+        abi_input
+            .known_preimages
+            .get(&topic)
+            .is_some_and(|preimage| regex.is_match(preimage))
+    });
+    preimage_matches
+        && abi_input
+            .filter
+            .as_ref()
+            .is_none_or(|filter| filter.matches_hash(topic))
 }
 
-fn decode_topic_log(topic: &[u8], abi_input: &ABIInput) -> Result<DecodedTopic, ()> {
-    let value = decode_topic_value(topic, abi_input);
+/// Indexed dynamic and reference types are not stored in the topic itself,
+/// only the keccak256 hash of their encoding.
+pub fn is_hashed_in_topic(sol_type: &DynSolType) -> bool {
+    matches!(
+        sol_type,
+        DynSolType::String
+            | DynSolType::Bytes
+            | DynSolType::Array(_)
+            | DynSolType::FixedArray(..)
+            | DynSolType::Tuple(_)
+    )
+}
 
-    // TODO: Do we need regex check here?
+/// Stores the hash of an indexed dynamic value as `<name>_hash`.
+/// If the hash belongs to one of the known preimages of the input, the preimage is stored as `<name>`.
+fn decode_hashed_topic(topic: B256, abi_input: &ABIInput) -> Vec<DecodedTopic> {
+    let mut results = vec![DecodedTopic {
+        name: format!("{}_hash", abi_input.name),
+        value: topic.to_string().into(),
+    }];
+
+    if let Some(preimage) = abi_input.known_preimages.get(&topic) {
+        results.push(DecodedTopic {
+            name: abi_input.name.clone(),
+            value: preimage.clone().into(),
+        });
+    }
 
-    Ok(DecodedTopic {
-        name: abi_input.name.clone(),
-        value,
-    })
+    results
 }
 
-fn decode_log_data(log: &Log, abi: &ABIItem) -> Result<Vec<DecodedTopic>, ()> {
+fn decode_log_data(log: &Log, abi: &ABIItem) -> Result<Option<Vec<DecodedTopic>>, DecodeError> {
     let non_indexed_inputs: Vec<&ABIInput> = abi
         .inputs
         .iter()
         .filter(|input| !input.indexed)
         .collect::<Vec<_>>();
 
-    // let topics = log.data.as_slice().chunks_exact(32);
-    let topics = log.data.data.chunks_exact(32);
-    if non_indexed_inputs.len() != topics.len() {
-        return Err(());
-    }
+    decode_inputs(non_indexed_inputs, &log.data.data)
+}
 
-    let mut results = Vec::<DecodedTopic>::new();
+/// Decodes the arguments of a function call, `input` being the calldata after the selector.
+/// Returns `None` if the arguments do not match the filters of the inputs.
+pub fn decode_call(input: &[u8], abi: &ABIItem) -> Result<Option<Vec<DecodedTopic>>, DecodeError> {
+    decode_inputs(abi.inputs.iter().collect(), input)
+}
 
-    for (i, topic) in topics.enumerate() {
-        let abi_input = non_indexed_inputs[i];
-        results.push(decode_topic_log(topic, abi_input)?);
+/// Decodes the inputs from the data as an ABI encoded tuple,
+/// so dynamic types (`string`, `bytes`, arrays) are resolved through their offsets.
+fn decode_inputs(
+    inputs: Vec<&ABIInput>,
+    data: &[u8],
+) -> Result<Option<Vec<DecodedTopic>>, DecodeError> {
+    let types = inputs
+        .iter()
+        .map(|input| parse_type(input))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let values = match DynSolType::Tuple(types).abi_decode_sequence(data) {
+        Ok(DynSolValue::Tuple(values)) => values,
+        Ok(_) => return Err(DecodeError::InvalidData("expected a tuple".to_string())),
+        Err(e) => return Err(DecodeError::InvalidData(e.to_string())),
+    };
+
+    if !inputs
+        .iter()
+        .zip(&values)
+        .all(|(abi_input, value)| input_matches(value, abi_input))
+    {
+        return Ok(None);
     }
 
-    Ok(results)
+    inputs
+        .into_iter()
+        .zip(values)
+        .map(|(abi_input, value)| {
+            Ok(DecodedTopic {
+                name: abi_input.name.clone(),
+                value: dyn_value_to_bson(value, abi_input)?,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
 }
 
-fn decode_numeric_string(topic: &[u8], is_signed: bool) -> Bson {
-    // Implementation that converts to String
-    // This doesn't need to be generic since it handles all larger sizes
-    if is_signed {
-        sol_data::Int::<256>::abi_decode(topic, true)
-            .unwrap()
-            .to_string()
-            .into()
+/// Decodes the return data of a view call. A single output is stored as is,
+/// several outputs as a document keyed by their names.
+pub fn decode_call_output(function: &Function, output: &[u8]) -> Result<Bson, DecodeError> {
+    let values = function
+        .abi_decode_output(output, true)
+        .map_err(|e| DecodeError::InvalidData(e.to_string()))?;
+    let outputs: Vec<ABIInput> = function
+        .outputs
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(ABIInput::from_param)
+        .collect();
+
+    let mut values: Vec<(String, Bson)> = outputs
+        .iter()
+        .zip(values)
+        .map(|(abi_input, value)| {
+            Ok((abi_input.name.clone(), dyn_value_to_bson(value, abi_input)?))
+        })
+        .collect::<Result<_, DecodeError>>()?;
+
+    if values.len() == 1 {
+        Ok(values.remove(0).1)
     } else {
-        sol_data::Uint::<256>::abi_decode(topic, true)
-            .unwrap()
-            .to_string()
-            .into()
+        Ok(Bson::Document(values.into_iter().collect()))
     }
 }
 
-fn decode_numeric_128<const BITS: usize>(topic: &[u8], is_signed: bool) -> Bson
-where
-    IntBitCount<BITS>: SupportedInt,
-{
-    if is_signed {
-        sol_data::Int::<BITS>::abi_decode(topic, true)
-            .unwrap()
-            .to_string()
-            .parse::<Decimal128>()
-            .unwrap()
-            .into()
-    } else {
-        let value = sol_data::Uint::<BITS>::abi_decode(topic, true)
-            .unwrap()
-            .to_string();
+fn parse_type(abi_input: &ABIInput) -> Result<DynSolType, DecodeError> {
+    DynSolType::parse(&abi_input.canonical_type())
+        .map_err(|_| DecodeError::UnsupportedType(abi_input.type_.clone()))
+}
 
-        match Decimal128::from_str(&value) {
-            Ok(decimal) => decimal.into(),
-            Err(_) => {
-                println!("Error parsing decimal: {}. Bits: {}", value, BITS);
-                value.into()
+/// Converts a decoded ABI value into its BSON representation.
+/// Arrays become BSON arrays, tuples nested documents keyed by the component names
+/// and `bytes` is stored as binary data. `bytesN` values are stored as hex strings,
+/// or as binary data if the input sets `storeAsBinary`.
+fn dyn_value_to_bson(value: DynSolValue, abi_input: &ABIInput) -> Result<Bson, DecodeError> {
+    match value {
+        DynSolValue::Address(address) => Ok(address.to_checksum(None).into()),
+        DynSolValue::Bool(value) => Ok(value.into()),
+        DynSolValue::Int(value, bits) => Ok(numeric_to_bson(value.to_string(), bits)),
+        DynSolValue::Uint(value, bits) => Ok(numeric_to_bson(value.to_string(), bits)),
+        DynSolValue::String(value) => Ok(value.into()),
+        DynSolValue::Bytes(bytes) => Ok(Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes,
+        })),
+        DynSolValue::FixedBytes(word, size) => {
+            // `bytesN` values are left-aligned, the rest of the word has to be zero
+            if word[size..].iter().any(|byte| *byte != 0) {
+                return Err(DecodeError::InvalidValue {
+                    input: abi_input.name.clone(),
+                    reason: format!("bytes{} value is not right-padded with zeros", size),
+                });
             }
+
+            let bytes = &word[..size];
+            if abi_input.store_as_binary {
+                Ok(Bson::Binary(Binary {
+                    subtype: BinarySubtype::Generic,
+                    bytes: bytes.to_vec(),
+                }))
+            } else {
+                Ok(hex::encode_prefixed(bytes).into())
+            }
+        }
+        // The elements of an array share the components of the array input
+        DynSolValue::Array(values) | DynSolValue::FixedArray(values) => Ok(Bson::Array(
+            values
+                .into_iter()
+                .map(|value| dyn_value_to_bson(value, abi_input))
+                .collect::<Result<_, _>>()?,
+        )),
+        DynSolValue::Tuple(values) => {
+            let components =
+                abi_input
+                    .components
+                    .as_ref()
+                    .ok_or_else(|| DecodeError::InvalidValue {
+                        input: abi_input.name.clone(),
+                        reason: "tuple without components".to_string(),
+                    })?;
+            if components.len() != values.len() {
+                return Err(DecodeError::InvalidValue {
+                    input: abi_input.name.clone(),
+                    reason: format!(
+                        "expected {} components, found {}",
+                        components.len(),
+                        values.len()
+                    ),
+                });
+            }
+
+            let mut document = Document::new();
+            for (component, value) in components.iter().zip(values) {
+                document.insert(&component.name, dyn_value_to_bson(value, component)?);
+            }
+            Ok(Bson::Document(document))
+        }
+        _ => Err(DecodeError::UnsupportedType(abi_input.type_.clone())),
+    }
+}
+
+/// Numbers up to 128 bits are stored as Decimal128, larger ones as strings.
+pub fn numeric_to_bson(value: String, bits: usize) -> Bson {
+    if bits > 128 {
+        return value.into();
+    }
+
+    match Decimal128::from_str(&value) {
+        Ok(decimal) => decimal.into(),
+        Err(_) => {
+            log::warn!("Error parsing decimal: {}. Bits: {}", value, bits);
+            value.into()
         }
     }
 }
 
-fn decode_topic_value(topic: &[u8], abi: &ABIInput) -> Bson {
-    match abi.type_.as_str() {
-        "address" => sol_data::Address::abi_decode(topic, true)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, keccak256, Bytes, U256};
+    use serde_json::json;
+
+    const TOKEN: Address = address!("a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+
+    fn abi_item(item: serde_json::Value) -> ABIItem {
+        serde_json::from_value(item).unwrap()
+    }
+
+    fn transfer(collection_name: &str, value_indexed: bool) -> ABIItem {
+        abi_item(json!({
+            "name": "Transfer",
+            "collectionName": collection_name,
+            "inputs": [
+                { "name": "from", "type": "address", "indexed": true },
+                { "name": "to", "type": "address", "indexed": true },
+                { "name": "value", "type": "uint256", "indexed": value_indexed },
+            ],
+        }))
+    }
+
+    fn log(log_index: u64, topics: Vec<B256>, data: Vec<u8>) -> (u64, Log) {
+        (
+            log_index,
+            Log::new_unchecked(TOKEN, topics, Bytes::from(data)),
+        )
+    }
+
+    fn address_topic(byte: u8) -> B256 {
+        B256::left_padding_from(&[byte; 20])
+    }
+
+    fn word(value: u64) -> B256 {
+        B256::from(U256::from(value))
+    }
+
+    fn decoded(result: &Result<DecodedLog, DecodeFailure>) -> &DecodedLog {
+        result.as_ref().expect("log should decode")
+    }
+
+    #[test]
+    fn transfers_are_routed_by_their_topic_count() {
+        let erc20 = transfer("erc20_transfers", false);
+        let erc721 = transfer("erc721_transfers", true);
+        let abi_items = [erc20.clone(), erc721.clone()];
+        assert_eq!(erc20.topic_id(), erc721.topic_id());
+
+        let topic_id = erc20.topic_id();
+        let logs = [
+            log(
+                0,
+                vec![topic_id, address_topic(1), address_topic(2)],
+                word(1000).to_vec(),
+            ),
+            log(
+                1,
+                vec![topic_id, address_topic(1), address_topic(2), word(7)],
+                Vec::new(),
+            ),
+        ];
+
+        let erc20_results = decode_logs(&logs, &erc20, &abi_items);
+        assert_eq!(erc20_results.len(), 1);
+        let erc20_log = decoded(&erc20_results[0]);
+        assert_eq!(erc20_log.log_index, 0);
+        assert_eq!(erc20_log.topics[2].name, "value");
+        assert_eq!(erc20_log.topics[2].value, Bson::String("1000".to_string()));
+
+        let erc721_results = decode_logs(&logs, &erc721, &abi_items);
+        assert_eq!(erc721_results.len(), 1);
+        let erc721_log = decoded(&erc721_results[0]);
+        assert_eq!(erc721_log.log_index, 1);
+        assert_eq!(erc721_log.topics[2].value, Bson::String("7".to_string()));
+    }
+
+    #[test]
+    fn logs_matching_no_variant_are_skipped() {
+        let abi_items = [
+            transfer("erc20_transfers", false),
+            transfer("erc721_transfers", true),
+        ];
+        let logs = [log(
+            0,
+            vec![abi_items[0].topic_id(), address_topic(1)],
+            [word(2).to_vec(), word(1000).to_vec()].concat(),
+        )];
+
+        for abi_item in &abi_items {
+            assert!(decode_logs(&logs, abi_item, &abi_items).is_empty());
+        }
+    }
+
+    #[test]
+    fn logs_of_unindexed_variants_are_skipped() {
+        let erc20 = transfer("erc20_transfers", false);
+        let topic_id = erc20.topic_id();
+        let logs = [
+            log(
+                0,
+                vec![topic_id, address_topic(1), address_topic(2), word(7)],
+                Vec::new(),
+            ),
+            log(
+                1,
+                vec![topic_id, address_topic(1), address_topic(2)],
+                word(1000).to_vec(),
+            ),
+        ];
+
+        let results = decode_logs(&logs, &erc20, &[erc20.clone()]);
+        assert_eq!(results.len(), 1);
+        assert_eq!(decoded(&results[0]).log_index, 1);
+    }
+
+    #[test]
+    fn anonymous_events_skip_signatures_of_other_items() {
+        let ping = abi_item(json!({
+            "name": "Ping",
+            "collectionName": "pings",
+            "inputs": [{ "name": "value", "type": "uint256" }],
+        }));
+        let anonymous = abi_item(json!({
+            "name": "Deposit",
+            "collectionName": "deposits",
+            "anonymous": true,
+            "inputs": [
+                { "name": "account", "type": "address", "indexed": true },
+                { "name": "value", "type": "uint256" },
+            ],
+        }));
+        let abi_items = [ping.clone(), anonymous.clone()];
+        let logs = [
+            log(0, vec![ping.topic_id()], word(1).to_vec()),
+            log(1, vec![address_topic(3)], word(2).to_vec()),
+        ];
+
+        let results = decode_logs(&logs, &anonymous, &abi_items);
+        assert_eq!(results.len(), 1);
+        let deposit = decoded(&results[0]);
+        assert_eq!(deposit.log_index, 1);
+        assert_eq!(deposit.topics[0].name, "account");
+    }
+
+    #[test]
+    fn fixed_bytes_have_to_be_right_padded() {
+        let item = abi_item(json!({
+            "name": "Called",
+            "collectionName": "calls",
+            "inputs": [{ "name": "selector", "type": "bytes4" }],
+        }));
+        let abi_items = [item.clone()];
+
+        let mut padded = [0; 32];
+        padded[..4].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        let mut unpadded = padded;
+        unpadded[31] = 1;
+        let logs = [
+            log(0, vec![item.topic_id()], padded.to_vec()),
+            log(1, vec![item.topic_id()], unpadded.to_vec()),
+        ];
+
+        let results = decode_logs(&logs, &item, &abi_items);
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            decoded(&results[0]).topics[0].value,
+            Bson::String("0x12345678".to_string())
+        );
+        assert!(matches!(
+            results[1],
+            Err(DecodeFailure {
+                log_index: 1,
+                error: DecodeError::InvalidValue { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn dynamic_arrays_are_decoded_from_the_data() {
+        let item = abi_item(json!({
+            "name": "TransferBatch",
+            "collectionName": "erc1155_batch_transfers",
+            "inputs": [
+                { "name": "operator", "type": "address", "indexed": true },
+                { "name": "from", "type": "address", "indexed": true },
+                { "name": "to", "type": "address", "indexed": true },
+                { "name": "ids", "type": "uint256[]" },
+                { "name": "values", "type": "uint256[]" },
+            ],
+        }));
+        let uints = |values: &[u64]| {
+            DynSolValue::Array(
+                values
+                    .iter()
+                    .map(|value| DynSolValue::Uint(U256::from(*value), 256))
+                    .collect(),
+            )
+        };
+        let data = DynSolValue::Tuple(vec![uints(&[1, 2, 3]), uints(&[10, 20, 30])]);
+        let logs = [log(
+            0,
+            vec![
+                item.topic_id(),
+                address_topic(1),
+                address_topic(2),
+                address_topic(3),
+            ],
+            data.abi_encode_params(),
+        )];
+
+        let results = decode_logs(&logs, &item, &[item.clone()]);
+        let batch = decoded(&results[0]);
+        assert_eq!(batch.topics[3].name, "ids");
+        assert_eq!(
+            batch.topics[3].value,
+            Bson::Array(vec!["1".into(), "2".into(), "3".into()])
+        );
+        assert_eq!(batch.topics[4].name, "values");
+        assert_eq!(
+            batch.topics[4].value,
+            Bson::Array(vec!["10".into(), "20".into(), "30".into()])
+        );
+    }
+
+    #[test]
+    fn strings_bytes_and_address_arrays_are_decoded_from_the_data() {
+        let item = abi_item(json!({
+            "name": "Broadcast",
+            "collectionName": "broadcasts",
+            "inputs": [
+                { "name": "sender", "type": "address", "indexed": true },
+                { "name": "message", "type": "string" },
+                { "name": "payload", "type": "bytes" },
+                { "name": "recipients", "type": "address[]" },
+            ],
+        }));
+        let recipients = [Address::repeat_byte(4), Address::repeat_byte(5)];
+        let data = DynSolValue::Tuple(vec![
+            DynSolValue::String("gm".to_string()),
+            DynSolValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+            DynSolValue::Array(recipients.map(DynSolValue::Address).to_vec()),
+        ]);
+        let logs = [log(
+            0,
+            vec![item.topic_id(), address_topic(1)],
+            data.abi_encode_params(),
+        )];
+
+        let results = decode_logs(&logs, &item, &[item.clone()]);
+        let broadcast = decoded(&results[0]);
+        assert_eq!(broadcast.topics[1].value, Bson::String("gm".to_string()));
+        assert_eq!(
+            broadcast.topics[2].value,
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: vec![0xde, 0xad, 0xbe, 0xef],
+            })
+        );
+        assert_eq!(
+            broadcast.topics[3].value,
+            Bson::Array(
+                recipients
+                    .iter()
+                    .map(|recipient| recipient.to_checksum(None).into())
+                    .collect()
+            )
+        );
+    }
+
+    fn registered(label: serde_json::Value) -> ABIItem {
+        abi_item(json!({
+            "name": "Registered",
+            "collectionName": "registrations",
+            "inputs": [label, { "name": "owner", "type": "address" }],
+        }))
+    }
+
+    fn registrations(item: &ABIItem, labels: &[&str]) -> Vec<(u64, Log)> {
+        labels
+            .iter()
+            .enumerate()
+            .map(|(i, label)| {
+                log(
+                    i as u64,
+                    vec![item.topic_id(), keccak256(label)],
+                    address_topic(1).to_vec(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn indexed_strings_are_stored_as_their_hash_and_known_preimages() {
+        let item = registered(json!({
+            "name": "label",
+            "type": "string",
+            "indexed": true,
+            "knownPreimages": ["vitalik"],
+        }));
+        let logs = registrations(&item, &["vitalik", "satoshi"]);
+
+        let results = decode_logs(&logs, &item, &[item.clone()]);
+        assert_eq!(results.len(), 2);
+
+        let known = decoded(&results[0]);
+        let names: Vec<&str> = known.topics.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["label_hash", "label", "owner"]);
+        assert_eq!(
+            known.topics[0].value,
+            Bson::String(keccak256("vitalik").to_string())
+        );
+        assert_eq!(known.topics[1].value, Bson::String("vitalik".to_string()));
+
+        let unknown = decoded(&results[1]);
+        let names: Vec<&str> = unknown.topics.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["label_hash", "owner"]);
+        assert_eq!(
+            unknown.topics[0].value,
+            Bson::String(keccak256("satoshi").to_string())
+        );
+    }
+
+    #[test]
+    fn regex_on_hashed_topics_only_matches_known_preimages() {
+        let item = registered(json!({
+            "name": "label",
+            "type": "string",
+            "indexed": true,
+            "rethRegexMatch": "^vit",
+            "knownPreimages": ["vitalik", "satoshi"],
+        }));
+        let logs = registrations(&item, &["satoshi", "vitalik", "vitamin"]);
+
+        let results = decode_logs(&logs, &item, &[item.clone()]);
+        assert_eq!(results.len(), 1);
+        let vitalik = decoded(&results[0]);
+        assert_eq!(vitalik.log_index, 1);
+        assert_eq!(vitalik.topics[1].value, Bson::String("vitalik".to_string()));
+    }
+
+    #[test]
+    fn equals_on_hashed_topics_compares_the_hash() {
+        let item = registered(json!({
+            "name": "label",
+            "type": "string",
+            "indexed": true,
+            "rethFilter": { "equals": "satoshi" },
+        }));
+        let logs = registrations(&item, &["vitalik", "satoshi"]);
+
+        let results = decode_logs(&logs, &item, &[item.clone()]);
+        assert_eq!(results.len(), 1);
+        let satoshi = decoded(&results[0]);
+        assert_eq!(satoshi.log_index, 1);
+        assert_eq!(
+            satoshi.topics[0].value,
+            Bson::String(keccak256("satoshi").to_string())
+        );
+    }
+
+    fn transfer_function(amount: serde_json::Value) -> ABIItem {
+        abi_item(json!({
+            "name": "transfer",
+            "collectionName": "transfer_calls",
+            "inputs": [
+                { "name": "to", "type": "address" },
+                { "name": "amount", "type": "uint256", "rethFilter": amount },
+            ],
+        }))
+    }
+
+    fn transfer_calldata(to: Address, amount: u64) -> Vec<u8> {
+        DynSolValue::Tuple(vec![
+            DynSolValue::Address(to),
+            DynSolValue::Uint(U256::from(amount), 256),
+        ])
+        .abi_encode_params()
+    }
+
+    #[test]
+    fn calls_are_decoded_from_the_calldata() {
+        let function = transfer_function(json!(null));
+        let to = Address::repeat_byte(7);
+
+        let arguments = decode_call(&transfer_calldata(to, 1000), &function)
             .unwrap()
-            .to_checksum(None)
-            .into(),
-        "bool" => sol_data::Bool::abi_decode(topic, true).unwrap().into(),
-        "bytes" => sol_data::Bytes::abi_decode(topic, true)
+            .expect("call should match");
+        assert_eq!(arguments[0].name, "to");
+        assert_eq!(arguments[0].value, Bson::String(to.to_checksum(None)));
+        assert_eq!(arguments[1].name, "amount");
+        assert_eq!(arguments[1].value, Bson::String("1000".to_string()));
+    }
+
+    #[test]
+    fn calls_not_matching_the_input_filters_are_skipped() {
+        let function = transfer_function(json!({ "gte": "1e6" }));
+        let to = Address::repeat_byte(7);
+
+        assert!(decode_call(&transfer_calldata(to, 1000), &function)
             .unwrap()
-            .to_string()
-            .into(),
-        "string" => sol_data::String::abi_decode(topic, true).unwrap().into(),
-        t if t.starts_with("uint") || t.starts_with("int") => {
-            let (bits, is_signed) = if t.starts_with("uint") {
-                (t[4..].parse::<usize>().unwrap(), false)
-            } else {
-                (t[3..].parse::<usize>().unwrap(), true)
-            };
+            .is_none());
+        assert!(decode_call(&transfer_calldata(to, 1_000_000), &function)
+            .unwrap()
+            .is_some());
+    }
 
-            if bits % 8 != 0 || bits == 0 || bits > 256 {
-                panic!("Invalid bit size: {}", bits);
-            }
+    #[test]
+    fn short_calldata_is_a_decode_error() {
+        let function = transfer_function(json!(null));
+        let calldata = transfer_calldata(Address::repeat_byte(7), 1000);
 
-            match bits {
-                // Use Decimal128 for bits <= 128
-                8 => decode_numeric_128::<8>(topic, is_signed),
-                16 => decode_numeric_128::<16>(topic, is_signed),
-                24 => decode_numeric_128::<24>(topic, is_signed),
-                32 => decode_numeric_128::<32>(topic, is_signed),
-                40 => decode_numeric_128::<40>(topic, is_signed),
-                48 => decode_numeric_128::<48>(topic, is_signed),
-                56 => decode_numeric_128::<56>(topic, is_signed),
-                64 => decode_numeric_128::<64>(topic, is_signed),
-                72 => decode_numeric_128::<72>(topic, is_signed),
-                80 => decode_numeric_128::<80>(topic, is_signed),
-                88 => decode_numeric_128::<88>(topic, is_signed),
-                96 => decode_numeric_128::<96>(topic, is_signed),
-                104 => decode_numeric_128::<104>(topic, is_signed),
-                112 => decode_numeric_128::<112>(topic, is_signed),
-                120 => decode_numeric_128::<120>(topic, is_signed),
-                128 => decode_numeric_128::<128>(topic, is_signed),
-                // Use String for bits > 128
-                136..=256 => decode_numeric_string(topic, is_signed),
-                // 136 => decode_numeric::<136>(topic, is_signed),
-                // 144 => decode_numeric::<144>(topic, is_signed),
-                // 152 => decode_numeric::<152>(topic, is_signed),
-                // 160 => decode_numeric::<160>(topic, is_signed),
-                // 168 => decode_numeric::<168>(topic, is_signed),
-                // 176 => decode_numeric::<176>(topic, is_signed),
-                // 184 => decode_numeric::<184>(topic, is_signed),
-                // 192 => decode_numeric::<192>(topic, is_signed),
-                // 200 => decode_numeric::<200>(topic, is_signed),
-                // 208 => decode_numeric::<208>(topic, is_signed),
-                // 216 => decode_numeric::<216>(topic, is_signed),
-                // 224 => decode_numeric::<224>(topic, is_signed),
-                // 232 => decode_numeric::<232>(topic, is_signed),
-                // 240 => decode_numeric::<240>(topic, is_signed),
-                // 248 => decode_numeric::<248>(topic, is_signed),
-                // 256 => decode_numeric::<256>(topic, is_signed),
-                _ => panic!("Unsupported bit size: {}", bits),
-            }
-        }
-        // "uint8" => sol_data::Uint::<8>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint16" => sol_data::Uint::<16>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint24" => sol_data::Uint::<24>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint32" => sol_data::Uint::<32>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint64" => sol_data::Uint::<64>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint112" => sol_data::Uint::<112>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint128" => sol_data::Uint::<128>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "uint256" => sol_data::Uint::<256>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int8" => sol_data::Int::<8>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int16" => sol_data::Int::<16>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int24" => sol_data::Int::<24>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int32" => sol_data::Int::<32>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int64" => sol_data::Int::<64>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int128" => sol_data::Int::<128>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        // "int256" => sol_data::Int::<256>::abi_decode(topic, true)
-        //     .unwrap()
-        //     .to_string(),
-        _ => panic!("Unknown type: {}", abi.type_),
+        // Only the recipient, the amount is cut off
+        let result = decode_call(&calldata[..32], &function);
+        assert!(matches!(result, Err(DecodeError::InvalidData(_))));
+        assert!(matches!(
+            decode_call(&[], &function),
+            Err(DecodeError::InvalidData(_))
+        ));
+    }
+
+    #[test]
+    fn a_single_call_output_is_stored_as_is() {
+        let symbol = Function::parse("function symbol() view returns (string)").unwrap();
+        let output =
+            DynSolValue::Tuple(vec![DynSolValue::String("WETH".to_string())]).abi_encode_params();
+
+        assert_eq!(
+            decode_call_output(&symbol, &output).unwrap(),
+            Bson::String("WETH".to_string())
+        );
+    }
+
+    #[test]
+    fn several_call_outputs_are_stored_as_a_document() {
+        let get_reserves = Function::parse(
+            "function getReserves() view returns (uint112 reserve0, uint112 reserve1, uint32)",
+        )
+        .unwrap();
+        let output = DynSolValue::Tuple(vec![
+            DynSolValue::Uint(U256::from(1000), 112),
+            DynSolValue::Uint(U256::from(2000), 112),
+            DynSolValue::Uint(U256::from(1_700_000_000), 32),
+        ])
+        .abi_encode_params();
+
+        let Bson::Document(reserves) = decode_call_output(&get_reserves, &output).unwrap() else {
+            panic!("several outputs should be decoded into a document");
+        };
+        let decimal = |value: &str| Bson::Decimal128(Decimal128::from_str(value).unwrap());
+        assert_eq!(reserves.get("reserve0"), Some(&decimal("1000")));
+        assert_eq!(reserves.get("reserve1"), Some(&decimal("2000")));
+        // Unnamed outputs are named by their position
+        assert_eq!(reserves.get("arg2"), Some(&decimal("1700000000")));
+    }
+
+    #[test]
+    fn undecodable_call_output_is_an_error() {
+        let symbol = Function::parse("function symbol() view returns (string)").unwrap();
+
+        assert!(matches!(
+            decode_call_output(&symbol, &[0x01, 0x02]),
+            Err(DecodeError::InvalidData(_))
+        ));
     }
 }
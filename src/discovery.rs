@@ -0,0 +1,188 @@
+use alloy::primitives::Address;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    config::{AddressDiscovery, IndexerContractMapping},
+    writer::BlockWrites,
+};
+
+/// Contracts created by factories, e.g. the pools of a Uniswap factory,
+/// whose logs are indexed by the mappings that set `discoverAddressesFrom`.
+///
+/// Shared between the workers and the writer. Children are added while blocks are processed
+/// and removed again when their block is rolled back by a reorg.
+#[derive(Debug, Default)]
+pub struct DiscoveredAddresses {
+    /// The block every child was created in, per factory event parameter.
+    children: RwLock<Children>,
+}
+
+type Children = HashMap<AddressDiscovery, HashMap<Address, u64>>;
+
+impl DiscoveredAddresses {
+    pub fn new(event_mappings: &[IndexerContractMapping]) -> Self {
+        let children = event_mappings
+            .iter()
+            .filter_map(|mapping| mapping.discover_addresses_from.clone())
+            .map(|discovery| (discovery, HashMap::new()))
+            .collect();
+
+        Self {
+            children: RwLock::new(children),
+        }
+    }
+
+    /// The factory event parameters that are tracked.
+    pub fn discoveries(&self) -> Vec<AddressDiscovery> {
+        self.read().keys().cloned().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// The number of children discovered from a factory event parameter.
+    pub fn len(&self, discovery: &AddressDiscovery) -> usize {
+        self.read().get(discovery).map_or(0, HashMap::len)
+    }
+
+    pub fn contains(&self, discovery: &AddressDiscovery, address: &Address) -> bool {
+        self.read()
+            .get(discovery)
+            .is_some_and(|children| children.contains_key(address))
+    }
+
+    pub fn insert(&self, discovery: &AddressDiscovery, address: Address, block_number: u64) {
+        if let Some(children) = self.write().get_mut(discovery) {
+            children.entry(address).or_insert(block_number);
+        }
+    }
+
+    /// Records the children created by the factory events of a processed block.
+    pub fn discover(&self, writes: &BlockWrites) {
+        let mut children = self.write();
+        for (discovery, addresses) in children.iter_mut() {
+            let Some(documents) = writes.documents.get(&discovery.collection_name) else {
+                continue;
+            };
+
+            for document in documents {
+                match document
+                    .get_str(&discovery.parameter)
+                    .map(Address::from_str)
+                {
+                    Ok(Ok(address)) => {
+                        addresses.entry(address).or_insert(writes.block_number);
+                    }
+                    _ => log::warn!(
+                        "No {} address in a {} document of block {}",
+                        discovery.parameter,
+                        discovery.collection_name,
+                        writes.block_number
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Forgets the children created from `fork_block` onwards.
+    pub fn rollback(&self, fork_block: u64) {
+        for addresses in self.write().values_mut() {
+            addresses.retain(|_, block_number| *block_number < fork_block);
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Children> {
+        self.children
+            .read()
+            .expect("Discovered addresses lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Children> {
+        self.children
+            .write()
+            .expect("Discovered addresses lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{address, B256};
+    use mongodb::bson::doc;
+    use serde_json::json;
+
+    const POOL_A: Address = address!("88e6a0c2ddd26feeb64f039a2c41296fcb3f5640");
+    const POOL_B: Address = address!("8ad599c3a0ff1de082011efddc58f1908eb6e6d8");
+
+    fn pools() -> AddressDiscovery {
+        AddressDiscovery {
+            collection_name: "univ3-pool-created".to_string(),
+            parameter: "pool".to_string(),
+        }
+    }
+
+    fn discovered() -> DiscoveredAddresses {
+        let mapping: IndexerContractMapping = serde_json::from_value(json!({
+            "discoverAddressesFrom": { "collectionName": "univ3-pool-created", "parameter": "pool" },
+        }))
+        .unwrap();
+        DiscoveredAddresses::new(&[mapping])
+    }
+
+    fn pool_created(block_number: u64, pool: Address) -> BlockWrites {
+        let mut writes = BlockWrites::new(block_number, B256::ZERO);
+        writes.add(
+            "univ3-pool-created",
+            vec![doc! { "pool": pool.to_string(), "fee": "3000" }],
+        );
+        writes
+    }
+
+    #[test]
+    fn children_are_discovered_from_factory_documents() {
+        let discovered = discovered();
+        discovered.discover(&pool_created(100, POOL_A));
+
+        assert!(discovered.contains(&pools(), &POOL_A));
+        assert!(!discovered.contains(&pools(), &POOL_B));
+        assert_eq!(discovered.len(&pools()), 1);
+
+        // Documents of other collections create no children
+        let mut writes = BlockWrites::new(101, B256::ZERO);
+        writes.add("swaps", vec![doc! { "pool": POOL_B.to_string() }]);
+        discovered.discover(&writes);
+        assert!(!discovered.contains(&pools(), &POOL_B));
+    }
+
+    #[test]
+    fn rollback_forgets_children_created_from_the_fork() {
+        let discovered = discovered();
+        discovered.discover(&pool_created(100, POOL_A));
+        discovered.discover(&pool_created(105, POOL_B));
+
+        discovered.rollback(105);
+        assert!(discovered.contains(&pools(), &POOL_A));
+        assert!(!discovered.contains(&pools(), &POOL_B));
+
+        discovered.rollback(90);
+        assert!(!discovered.contains(&pools(), &POOL_A));
+        assert_eq!(discovered.len(&pools()), 0);
+
+        // The factory is still tracked, the new canonical blocks discover the children again
+        discovered.discover(&pool_created(106, POOL_B));
+        assert!(discovered.contains(&pools(), &POOL_B));
+    }
+
+    #[test]
+    fn a_child_keeps_the_block_it_was_first_created_in() {
+        let discovered = discovered();
+        discovered.discover(&pool_created(100, POOL_A));
+        discovered.discover(&pool_created(110, POOL_A));
+
+        discovered.rollback(110);
+        assert!(discovered.contains(&pools(), &POOL_A));
+    }
+}
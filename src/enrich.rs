@@ -0,0 +1,265 @@
+use alloy::{
+    json_abi::Function,
+    primitives::{Address, Selector, TxKind, U256},
+};
+use mongodb::bson::Bson;
+use reth_chainspec::{ChainSpec, ChainSpecProvider};
+use reth_evm::ConfigureEvmEnv;
+use reth_node_ethereum::EthEvmConfig;
+use reth_primitives::Header;
+use reth_provider::{providers::ProviderNodeTypes, HeaderProvider, ProviderFactory, StateProvider};
+use reth_revm::{
+    database::StateProviderDatabase,
+    primitives::{
+        BlockEnv, CfgEnv, CfgEnvWithHandlerCfg, EVMError, EnvWithHandlerCfg, ExecutionResult,
+        SpecId, TxEnv,
+    },
+    Evm,
+};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{config::IndexerContractMapping, decoder::decode_call_output, writer::BlockWrites};
+
+/// Gas available to a single view call.
+const CALL_GAS_LIMIT: u64 = 30_000_000;
+
+/// Results of view calls by called address and function selector.
+/// Calls that revert are cached as `null`, so they are not executed again.
+#[derive(Debug, Default)]
+pub struct CallCache {
+    results: RwLock<HashMap<(Address, Selector), Bson>>,
+}
+
+impl CallCache {
+    pub fn get(&self, address: Address, selector: Selector) -> Option<Bson> {
+        self.read().get(&(address, selector)).cloned()
+    }
+
+    pub fn insert(&self, address: Address, selector: Selector, result: Bson) {
+        self.write().insert((address, selector), result);
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, HashMap<(Address, Selector), Bson>> {
+        self.results.read().expect("Call cache lock poisoned")
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, HashMap<(Address, Selector), Bson>> {
+        self.results.write().expect("Call cache lock poisoned")
+    }
+}
+
+/// Adds the results of the `enrichWithCalls` of the mapping's ABI items to their documents
+/// of a block. The calls are executed against the state after the block.
+pub fn enrich_documents<N: ProviderNodeTypes<ChainSpec = ChainSpec>>(
+    factory: &ProviderFactory<N>,
+    mapping: &IndexerContractMapping,
+    header: &Header,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    let abi_items: Vec<_> = mapping
+        .decode_abi_items
+        .iter()
+        .chain(&mapping.decode_function_items)
+        .filter(|abi_item| {
+            !abi_item.enrich_with_calls.is_empty()
+                && writes.documents.contains_key(&abi_item.collection_name)
+        })
+        .collect();
+    if abi_items.is_empty() {
+        return Ok(());
+    }
+
+    let env = block_env(factory, header)?;
+    let state = factory.history_by_block_number(header.number)?;
+    for abi_item in abi_items {
+        let Some(documents) = writes.documents.get_mut(&abi_item.collection_name) else {
+            continue;
+        };
+
+        for document in documents {
+            for call in &abi_item.enrich_with_calls {
+                let address = match document.get_str(&call.address_field).map(Address::from_str) {
+                    Ok(Ok(address)) => address,
+                    _ => {
+                        log::warn!(
+                            "No {} address in a {} document of block {}",
+                            call.address_field,
+                            abi_item.collection_name,
+                            header.number
+                        );
+                        continue;
+                    }
+                };
+
+                let selector = call.function.selector();
+                let cached = call
+                    .cache
+                    .then(|| mapping.call_cache.get(address, selector))
+                    .flatten();
+                let result = match cached {
+                    Some(result) => result,
+                    None => {
+                        let result = view_call(&state, &env, header, address, &call.function)?;
+                        if call.cache {
+                            mapping.call_cache.insert(address, selector, result.clone());
+                        }
+                        result
+                    }
+                };
+                document.insert(&call.field, result);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The EVM environment of a block: the spec of its hardfork, its chain id and block values
+/// like the coinbase and prevrandao, as the node executed it.
+fn block_env<N: ProviderNodeTypes<ChainSpec = ChainSpec>>(
+    factory: &ProviderFactory<N>,
+    header: &Header,
+) -> eyre::Result<EnvWithHandlerCfg> {
+    // Pre-merge hardforks are activated by the total difficulty
+    let total_difficulty = factory
+        .header_td_by_number(header.number)?
+        .ok_or_else(|| eyre::eyre!("No total difficulty for block {}", header.number))?;
+
+    let mut cfg = CfgEnvWithHandlerCfg::new_with_spec_id(CfgEnv::default(), SpecId::LATEST);
+    let mut block_env = BlockEnv::default();
+    EthEvmConfig::new(factory.chain_spec()).fill_cfg_and_block_env(
+        &mut cfg,
+        &mut block_env,
+        header,
+        total_difficulty,
+    );
+    // Like `eth_call` without a gas price, the caller does not pay the base fee
+    block_env.basefee = U256::ZERO;
+
+    Ok(EnvWithHandlerCfg::new_with_cfg_env(
+        cfg,
+        block_env,
+        TxEnv::default(),
+    ))
+}
+
+/// Executes a call without arguments like `eth_call` and decodes its return data.
+/// Returns `null` if the call fails or returns something else than the function outputs,
+/// only failing to read the state is an error.
+fn view_call<S: StateProvider>(
+    state: S,
+    env: &EnvWithHandlerCfg,
+    header: &Header,
+    address: Address,
+    function: &Function,
+) -> eyre::Result<Bson> {
+    let mut env = env.clone();
+    env.tx.transact_to = TxKind::Call(address);
+    env.tx.data = function.selector().to_vec().into();
+    env.tx.gas_limit = CALL_GAS_LIMIT.min(header.gas_limit);
+
+    let mut evm = Evm::builder()
+        .with_db(StateProviderDatabase::new(state))
+        .with_env_with_handler_cfg(env)
+        .build();
+
+    let result = match evm.transact() {
+        Ok(result) => result.result,
+        Err(EVMError::Database(e)) => {
+            return Err(eyre::eyre!(
+                "Failed to read the state for {} on {}: {}",
+                function.name,
+                address,
+                e
+            ))
+        }
+        Err(e) => {
+            log::warn!(
+                "Call of {} on {} failed in block {}: {}",
+                function.name,
+                address,
+                header.number,
+                e
+            );
+            return Ok(Bson::Null);
+        }
+    };
+
+    let output = match result {
+        ExecutionResult::Success { output, .. } => output.into_data(),
+        _ => {
+            log::warn!(
+                "Call of {} on {} reverted in block {}",
+                function.name,
+                address,
+                header.number
+            );
+            return Ok(Bson::Null);
+        }
+    };
+
+    Ok(decode_output(function, &output, address, header.number))
+}
+
+/// Decodes the return data of a call, `null` if it does not match the function outputs.
+fn decode_output(function: &Function, output: &[u8], address: Address, block_number: u64) -> Bson {
+    match decode_call_output(function, output) {
+        Ok(result) => result,
+        Err(e) => {
+            log::warn!(
+                "Failed to decode {} of {} in block {}: {}",
+                function.name,
+                address,
+                block_number,
+                e
+            );
+            Bson::Null
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::{dyn_abi::DynSolValue, primitives::B256};
+
+    const TOKEN: Address = Address::repeat_byte(0xaa);
+
+    #[test]
+    fn undecodable_return_data_is_null() {
+        let symbol = Function::parse("function symbol() view returns (string)").unwrap();
+        let output =
+            DynSolValue::Tuple(vec![DynSolValue::String("USDC".to_string())]).abi_encode_params();
+
+        assert_eq!(
+            decode_output(&symbol, &output, TOKEN, 1),
+            Bson::String("USDC".to_string())
+        );
+        // E.g. a contract without the function whose fallback returns nothing
+        assert_eq!(decode_output(&symbol, &[], TOKEN, 1), Bson::Null);
+        // Old tokens return their symbol as bytes32
+        let output = DynSolValue::FixedBytes(B256::right_padding_from(b"MKR"), 32).abi_encode();
+        assert_eq!(decode_output(&symbol, &output, TOKEN, 1), Bson::Null);
+    }
+
+    #[test]
+    fn results_are_cached_by_address_and_selector() {
+        let cache = CallCache::default();
+        let symbol = Function::parse("function symbol() view returns (string)").unwrap();
+        let name = Function::parse("function name() view returns (string)").unwrap();
+
+        assert_eq!(cache.get(TOKEN, symbol.selector()), None);
+        cache.insert(TOKEN, symbol.selector(), Bson::String("USDC".to_string()));
+        // Failed calls are cached too
+        cache.insert(TOKEN, name.selector(), Bson::Null);
+
+        assert_eq!(
+            cache.get(TOKEN, symbol.selector()),
+            Some(Bson::String("USDC".to_string()))
+        );
+        assert_eq!(cache.get(TOKEN, name.selector()), Some(Bson::Null));
+        assert_eq!(cache.get(Address::ZERO, symbol.selector()), None);
+    }
+}
@@ -0,0 +1,386 @@
+use alloy::{
+    dyn_abi::{DynSolType, DynSolValue},
+    primitives::{hex, keccak256, B256, U256},
+};
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+
+use crate::decoder::is_hashed_in_topic;
+
+/// Predicates on the decoded value of an input. Logs with a value that does not match
+/// every set predicate are dropped before they are written to MongoDB.
+#[derive(Debug, Deserialize, Clone)]
+pub struct InputFilter {
+    /// The value has to be equal to this one.
+    pub equals: Option<String>,
+
+    /// The value has to be equal to one of these.
+    #[serde(rename = "in")]
+    pub in_: Option<Vec<String>>,
+
+    /// Numeric bounds, e.g. `"1e18"`.
+    #[serde(default, deserialize_with = "deserialize_number_option")]
+    pub gt: Option<Number>,
+    #[serde(default, deserialize_with = "deserialize_number_option")]
+    pub gte: Option<Number>,
+    #[serde(default, deserialize_with = "deserialize_number_option")]
+    pub lt: Option<Number>,
+    #[serde(default, deserialize_with = "deserialize_number_option")]
+    pub lte: Option<Number>,
+}
+
+impl InputFilter {
+    /// The values an indexed input has to equal, if the filter restricts it to a list.
+    pub fn allowed_values(&self) -> Option<Vec<&str>> {
+        match (&self.equals, &self.in_) {
+            (Some(equals), None) => Some(vec![equals.as_str()]),
+            (None, Some(values)) => Some(values.iter().map(String::as_str).collect()),
+            (Some(equals), Some(values)) if values.contains(equals) => Some(vec![equals.as_str()]),
+            // Both are set but disagree, nothing can match
+            (Some(_), Some(_)) => Some(Vec::new()),
+            (None, None) => None,
+        }
+    }
+
+    pub fn matches(&self, value: &DynSolValue) -> bool {
+        if let Some(allowed) = self.allowed_values() {
+            if !allowed.iter().any(|expected| value_equals(value, expected)) {
+                return false;
+            }
+        }
+
+        let bounds = [
+            (&self.gt, &[Ordering::Greater][..]),
+            (&self.gte, &[Ordering::Greater, Ordering::Equal][..]),
+            (&self.lt, &[Ordering::Less][..]),
+            (&self.lte, &[Ordering::Less, Ordering::Equal][..]),
+        ];
+        for (bound, accepted) in bounds {
+            if let Some(bound) = bound {
+                match Number::from_value(value) {
+                    Some(number) if accepted.contains(&number.cmp(bound)) => {}
+                    _ => return false,
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Indexed strings and bytes are only stored as their hash, so the expected values
+    /// are hashed and compared with the topic. Numeric bounds never match a hash.
+    pub fn matches_hash(&self, topic: B256) -> bool {
+        if self.gt.is_some() || self.gte.is_some() || self.lt.is_some() || self.lte.is_some() {
+            return false;
+        }
+
+        match self.allowed_values() {
+            Some(allowed) => allowed
+                .iter()
+                .any(|expected| preimage_hashes(expected).contains(&topic)),
+            None => true,
+        }
+    }
+}
+
+/// The topics an indexed input may have according to its `equals` and `in` values.
+/// Returns `None` if the filter does not restrict the input to a list of values.
+pub fn topic_values(
+    sol_type: &DynSolType,
+    filter: &InputFilter,
+) -> Result<Option<Vec<B256>>, String> {
+    let Some(allowed) = filter.allowed_values() else {
+        return Ok(None);
+    };
+
+    let mut topics = Vec::new();
+    for value in allowed {
+        if is_hashed_in_topic(sol_type) {
+            topics.extend(preimage_hashes(value));
+            continue;
+        }
+
+        let topic = match sol_type {
+            DynSolType::Int(_) | DynSolType::Uint(_) => value.parse::<Number>()?.to_word(),
+            _ => sol_type
+                .coerce_str(value)
+                .ok()
+                .and_then(|value| value.as_word())
+                .ok_or_else(|| format!("{} is not a valid {}", value, sol_type))?,
+        };
+        topics.push(topic);
+    }
+    Ok(Some(topics))
+}
+
+/// Checks a decoded value against a regex.
+/// Addresses are matched in their checksum form, numbers in decimal and bytes as `0x` prefixed hex.
+pub fn regex_matches(regex: &Regex, value: &DynSolValue) -> bool {
+    value_to_string(value).is_some_and(|value| regex.is_match(&value))
+}
+
+/// The hashes a value can have in a topic: of the string itself and, if it is hex, of its bytes.
+pub fn preimage_hashes(preimage: &str) -> Vec<B256> {
+    let mut hashes = vec![keccak256(preimage.as_bytes())];
+    if let Ok(bytes) = hex::decode(preimage) {
+        hashes.push(keccak256(bytes));
+    }
+    hashes
+}
+
+fn value_equals(value: &DynSolValue, expected: &str) -> bool {
+    match value {
+        DynSolValue::Int(..) | DynSolValue::Uint(..) => {
+            match (Number::from_value(value), expected.parse::<Number>()) {
+                (Some(value), Ok(expected)) => value == expected,
+                _ => false,
+            }
+        }
+        DynSolValue::String(value) => value == expected,
+        // Addresses and bytes are compared case-insensitive, so checksums do not matter
+        _ => value_to_string(value).is_some_and(|value| value.eq_ignore_ascii_case(expected)),
+    }
+}
+
+fn value_to_string(value: &DynSolValue) -> Option<String> {
+    match value {
+        DynSolValue::Address(address) => Some(address.to_checksum(None)),
+        DynSolValue::Bool(value) => Some(value.to_string()),
+        DynSolValue::Int(value, _) => Some(value.to_string()),
+        DynSolValue::Uint(value, _) => Some(value.to_string()),
+        DynSolValue::String(value) => Some(value.clone()),
+        DynSolValue::Bytes(bytes) => Some(hex::encode_prefixed(bytes)),
+        DynSolValue::FixedBytes(word, size) => Some(hex::encode_prefixed(&word[..*size])),
+        _ => None,
+    }
+}
+
+/// An integer that covers both `int256` and `uint256` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Number {
+    negative: bool,
+    magnitude: U256,
+}
+
+impl Number {
+    fn from_value(value: &DynSolValue) -> Option<Self> {
+        match value {
+            DynSolValue::Uint(value, _) => Some(Self {
+                negative: false,
+                magnitude: *value,
+            }),
+            DynSolValue::Int(value, _) => Some(Self {
+                negative: value.is_negative(),
+                magnitude: value.unsigned_abs(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The value as a 32 byte word, negative numbers in two's complement.
+    fn to_word(self) -> B256 {
+        let word = if self.negative {
+            self.magnitude.wrapping_neg()
+        } else {
+            self.magnitude
+        };
+        B256::from(word.to_be_bytes::<32>())
+    }
+}
+
+impl Ord for Number {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.magnitude.cmp(&other.magnitude),
+            (true, true) => other.magnitude.cmp(&self.magnitude),
+            // Zero is never stored as negative
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl std::str::FromStr for Number {
+    type Err = String;
+
+    /// Parses integers like `1000`, `-5`, `1e18` or `2.5e18`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid number: {}", s);
+
+        let (negative, unsigned) = match s.trim().strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, s.trim()),
+        };
+        let (mantissa, exponent) = match unsigned.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => {
+                (mantissa, exponent.parse::<usize>().map_err(|_| invalid())?)
+            }
+            None => (unsigned, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > exponent {
+            return Err(format!("{} is not an integer", s));
+        }
+
+        let digits = format!("{}{}", integer, fraction);
+        let magnitude = U256::from_str_radix(&digits, 10)
+            .ok()
+            .and_then(|value| {
+                U256::from(10)
+                    .checked_pow(U256::from(exponent - fraction.len()))
+                    .and_then(|scale| value.checked_mul(scale))
+            })
+            .ok_or_else(invalid)?;
+
+        Ok(Self {
+            negative: negative && !magnitude.is_zero(),
+            magnitude,
+        })
+    }
+}
+
+fn deserialize_number_option<'de, D>(deserializer: D) -> Result<Option<Number>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Compiles the `rethRegexMatch` of an input when the config is loaded.
+pub fn deserialize_regex_option<'de, D>(deserializer: D) -> Result<Option<Regex>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| Regex::new(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(s: &str) -> Number {
+        s.parse().unwrap()
+    }
+
+    fn filter(json: &str) -> InputFilter {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn parses_integers_with_exponents() {
+        let wei = U256::from(10).pow(U256::from(18));
+        assert_eq!(number("1e18").magnitude, wei);
+        assert_eq!(
+            number("2.5e18").magnitude,
+            wei * U256::from(5) / U256::from(2)
+        );
+        assert_eq!(number("1000").magnitude, U256::from(1000));
+        assert!(!number("1e18").negative);
+    }
+
+    #[test]
+    fn parses_negative_numbers() {
+        let value = number("-5");
+        assert!(value.negative);
+        assert_eq!(value.magnitude, U256::from(5));
+        // Zero is never negative
+        assert_eq!(number("-0"), number("0"));
+    }
+
+    #[test]
+    fn rejects_fractions_and_invalid_numbers() {
+        assert!("1.5".parse::<Number>().is_err());
+        assert!("2.55e1".parse::<Number>().is_err());
+        assert!("abc".parse::<Number>().is_err());
+        assert!("1e".parse::<Number>().is_err());
+    }
+
+    #[test]
+    fn orders_signed_numbers() {
+        assert!(number("-5") < number("-1"));
+        assert!(number("-1") < number("0"));
+        assert!(number("0") < number("1e18"));
+        assert!(number("2e18") > number("1e18"));
+    }
+
+    #[test]
+    fn negative_int_topics_are_twos_complement() {
+        let topics = topic_values(&DynSolType::Int(256), &filter(r#"{"equals": "-1"}"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(topics, vec![B256::repeat_byte(0xff)]);
+
+        let topics = topic_values(&DynSolType::Int(256), &filter(r#"{"equals": "-5"}"#))
+            .unwrap()
+            .unwrap();
+        let mut expected = [0xff; 32];
+        expected[31] = 0xfb;
+        assert_eq!(topics, vec![B256::from(expected)]);
+    }
+
+    #[test]
+    fn uint_and_address_topics() {
+        let topics = topic_values(&DynSolType::Uint(256), &filter(r#"{"in": ["1", "1e3"]}"#))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            topics,
+            vec![
+                B256::from(U256::from(1).to_be_bytes::<32>()),
+                B256::from(U256::from(1000).to_be_bytes::<32>()),
+            ]
+        );
+
+        let address = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f";
+        let topics = topic_values(
+            &DynSolType::Address,
+            &filter(&format!(r#"{{"equals": "{}"}}"#, address)),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(topics[0][12..], hex::decode(address).unwrap()[..]);
+    }
+
+    #[test]
+    fn unrestricted_filters_have_no_topics() {
+        let topics = topic_values(&DynSolType::Uint(256), &filter(r#"{"gte": "1"}"#)).unwrap();
+        assert!(topics.is_none());
+    }
+
+    #[test]
+    fn bounds_are_checked() {
+        let filter = filter(r#"{"gte": "1e18", "lt": "2e18"}"#);
+        let uint = |s: &str| DynSolValue::Uint(number(s).magnitude, 256);
+
+        assert!(!filter.matches(&uint("999999999999999999")));
+        assert!(filter.matches(&uint("1e18")));
+        assert!(filter.matches(&uint("1.5e18")));
+        assert!(!filter.matches(&uint("2e18")));
+        // Bounds never match values that are not numbers
+        assert!(!filter.matches(&DynSolValue::Bool(true)));
+    }
+
+    #[test]
+    fn bounds_of_signed_values() {
+        let filter = filter(r#"{"gte": "-10", "lt": "0"}"#);
+        let int =
+            |value: i64| DynSolValue::Int(alloy::primitives::I256::try_from(value).unwrap(), 256);
+
+        assert!(filter.matches(&int(-10)));
+        assert!(filter.matches(&int(-1)));
+        assert!(!filter.matches(&int(-11)));
+        assert!(!filter.matches(&int(0)));
+    }
+}
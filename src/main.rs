@@ -1,36 +1,81 @@
-use ::mongodb::Database;
-use alloy::primitives::{keccak256, Address, Bloom, B256};
+use alloy::genesis::Genesis;
+use alloy::primitives::{Address, Bloom, B256};
 use alloy::rpc::types::{FilterSet, FilteredParams};
-use config::{ABIItem, IndexerConfig, IndexerContractMapping};
-use decoder::decode_logs;
-use indicatif::{ProgressBar, ProgressStyle};
+use config::{
+    ABIItem, ContractDeployments, DecodeErrorPolicy, IndexerConfig, IndexerContractMapping,
+    NativeTransfers, StateChanges,
+};
+use decoder::{decode_call, decode_logs};
+use discovery::DiscoveredAddresses;
+use enrich::enrich_documents;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log::info;
-use mongodb::{init_mongodb, insert_logs};
-use reth_chainspec::ChainSpecBuilder;
+use mongodb::{
+    account_change_document, call_document, create_collections, dead_letter_call_document,
+    dead_letter_documents, deployment_document, init_mongodb, load_checkpoints, log_documents,
+    native_transfer_document, reset_checkpoints, storage_change_document, tx_context, TxContext,
+    DEAD_LETTERS_COLLECTION,
+};
+use reorg::BlockWindow;
+use reth_chainspec::{ChainSpec, DEV, HOLESKY, MAINNET, SEPOLIA};
 use reth_db::mdbx::{DatabaseArguments, MaxReadTransactionDuration};
 use reth_db::{open_db_read_only, DatabaseEnv};
 use reth_node_ethereum::EthereumNode;
 use reth_node_types::NodeTypesWithDBAdapter;
-use reth_primitives::{Header, Log};
+use reth_primitives::{Header, Log, Receipt, TransactionSignedNoHash};
 use reth_provider::{
-    providers::StaticFileProvider, BlockReader, HeaderProvider, ProviderFactory, ReceiptProvider,
-    TransactionsProvider,
+    providers::{ProviderNodeTypes, StaticFileProvider},
+    AccountReader, BlockHashReader, BlockNumReader, BlockReader, ChangeSetReader, HeaderProvider,
+    ProviderFactory, ReceiptProvider, StateProvider, StorageReader, TransactionsProvider,
 };
 use std::fs::File;
 use std::io::Read;
-use std::time::Instant;
+use std::ops::Range;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use std::{path::Path, sync::Arc};
+use tokio::sync::{mpsc, watch};
+use writer::{BlockWrites, Writer};
 
 mod config;
 mod decoder;
+mod discovery;
+mod enrich;
+mod filter;
 mod mongodb;
+mod reorg;
+mod writer;
+
+/// How many processed blocks may wait for the writer before the workers are paused.
+const WRITE_QUEUE_SIZE: usize = 1_024;
+
+/// Set once Ctrl-C is pressed, shared by every source. Being a state rather than a signal
+/// future, a Ctrl-C pressed while a source is busy writing is seen at its next check.
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn shutdown_sender() -> &'static watch::Sender<bool> {
+    SHUTDOWN.get_or_init(|| watch::channel(false).0)
+}
+
+fn shutdown_requested() -> bool {
+    *shutdown_sender().borrow()
+}
+
+/// Resolves once Ctrl-C is pressed, immediately if it already was.
+async fn shutdown() {
+    let mut receiver = shutdown_sender().subscribe();
+    // The sender is static, so the channel is never closed
+    let _ = receiver.wait_for(|shutdown| *shutdown).await;
+}
 
 // Univ2 factory 10000835
 
 /// Loads the indexer configuration from the "reth-indexer-config.json" file.
-/// Returns the loaded `IndexerConfig` if successful.
+/// Returns one `IndexerConfig` per source if successful.
 /// Panics if the file does not exist or if there is an error reading or parsing the file.
-fn load_indexer_config(file_path: &Path) -> IndexerConfig {
+fn load_indexer_config(file_path: &Path) -> Vec<IndexerConfig> {
     let mut file = File::open(file_path)
         .unwrap_or_else(|_| panic!("Failed to find config file at path - {:?}", file_path));
 
@@ -41,26 +86,115 @@ fn load_indexer_config(file_path: &Path) -> IndexerConfig {
     let config: IndexerConfig =
         serde_json::from_str(&content).expect("Failed to parse config.json JSON");
 
-    config
+    let config_dir = file_path.parent().unwrap_or(Path::new("."));
+    let mut configs = config.into_sources();
+    for config in &mut configs {
+        if let Err(e) = config.resolve_abi_items(config_dir) {
+            panic!("Failed to load ABI items - {}", e);
+        }
+
+        if let Err(e) = config.validate() {
+            panic!("Invalid config - {}", e);
+        }
+    }
+
+    configs
 }
 
-#[tokio::main]
-async fn main() -> eyre::Result<()> {
+fn main() -> eyre::Result<()> {
+    // Warnings are printed by default, `RUST_LOG=info` also logs every block and flush
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
     let config: String = std::env::var("CONFIG").unwrap_or("./config.json".to_string());
     println!("Config: {}", config);
 
-    let log_config: IndexerConfig = load_indexer_config(Path::new(&config));
-    println!("log_config: {:#?}", log_config);
+    let log_configs: Vec<IndexerConfig> = load_indexer_config(Path::new(&config));
+    println!("log_config: {:#?}", log_configs);
+
+    // One handler for the whole process, the sources check the shared shutdown state.
+    // It replaces the default handler, so a second Ctrl-C has to exit the process itself
+    // when a flush or catch-up does not stop.
+    std::thread::spawn(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build();
+        let Ok(runtime) = runtime else {
+            return;
+        };
+        runtime.block_on(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
+            }
+            shutdown_sender().send_replace(true);
+            eprintln!("Stopping after the next flush, press Ctrl-C again to exit immediately");
 
-    sync(&log_config).await?;
+            if tokio::signal::ctrl_c().await.is_ok() {
+                eprintln!("Exiting without flushing, the sync resumes from the last checkpoint");
+                std::process::exit(130);
+            }
+        });
+    });
 
-    Ok(())
+    let specs = log_configs
+        .iter()
+        .map(|config| load_chain_spec(&config.chain))
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    // Sources can share databases and collections, so they are created before any source starts
+    let source_chains: Vec<(&IndexerConfig, u64)> = log_configs
+        .iter()
+        .zip(&specs)
+        .map(|(config, spec)| (config, spec.chain.id()))
+        .collect();
+    if let Err(e) = IndexerConfig::validate_sources(&source_chains) {
+        panic!("Invalid config - {}", e);
+    }
+    tokio::runtime::Runtime::new()?.block_on(create_collections(&source_chains))?;
+    info!("Initialized MongoDB");
+
+    // Every source runs on its own thread and runtime, so a source following its tip
+    // never holds up the others
+    let progress = MultiProgress::new();
+    let sources: Vec<_> = log_configs
+        .into_iter()
+        .zip(specs)
+        .map(|(config, spec)| {
+            let progress = progress.clone();
+            std::thread::spawn(move || -> eyre::Result<()> {
+                let name = config.source_name().to_string();
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                    tokio::runtime::Runtime::new()?.block_on(sync(Arc::new(config), spec, progress))
+                }))
+                .unwrap_or_else(|_| Err(eyre::eyre!("Source thread panicked")));
+                if let Err(e) = &result {
+                    eprintln!("Source {} stopped: {:?}", name, e);
+                    // The other sources flush their buffers and stop as on Ctrl-C
+                    shutdown_sender().send_replace(true);
+                }
+                result
+            })
+        })
+        .collect();
+
+    // Every source is waited for, the process fails with the first error once all have stopped
+    let results: Vec<eyre::Result<()>> = sources
+        .into_iter()
+        .map(|source| {
+            source
+                .join()
+                .unwrap_or_else(|_| Err(eyre::eyre!("Source thread panicked")))
+        })
+        .collect();
+    results.into_iter().collect()
 }
-async fn sync(config: &IndexerConfig) -> eyre::Result<()> {
-    info!("Starting indexer...");
+async fn sync(
+    config: Arc<IndexerConfig>,
+    spec: Arc<ChainSpec>,
+    progress: MultiProgress,
+) -> eyre::Result<()> {
+    info!("Starting indexer for {}...", config.source_name());
 
     let from_block = config.from_block;
-    let to_block = config.to_block;
 
     let db_path = Path::new(&config.reth_db_location);
 
@@ -71,59 +205,812 @@ async fn sync(config: &IndexerConfig) -> eyre::Result<()> {
         .with_max_read_transaction_duration(Some(MaxReadTransactionDuration::Unbounded));
     let db = open_db_read_only(db_path.join("db").as_path(), database_args)?;
 
-    let mongodb = init_mongodb(&config.mongodb, &config.event_mappings).await?;
-    info!("Initialized MongoDB");
-    let spec = ChainSpecBuilder::mainnet().build();
+    let mongodb = init_mongodb(&config.mongodb).await?;
+    let chain_id = spec.chain.id();
+    println!(
+        "[{}] Chain: {} (chain id {})",
+        config.source_name(),
+        config.chain,
+        chain_id
+    );
     let factory = ProviderFactory::<NodeTypesWithDBAdapter<EthereumNode, Arc<DatabaseEnv>>>::new(
         db.into(),
-        spec.into(),
+        spec,
         StaticFileProvider::read_only(db_path.join("static_files"), true)?,
     );
 
+    if config.force_fresh_sync {
+        reset_checkpoints(&mongodb, chain_id, &config.event_mappings).await?;
+        info!("Checkpoints reset, starting a fresh sync");
+    }
+
+    // Resume every mapping right after its last fully processed block
+    let checkpoints = load_checkpoints(&mongodb, chain_id, &config.event_mappings).await?;
+    let start_block = checkpoints
+        .iter()
+        .map(|checkpoint| checkpoint.map_or(from_block, |block| block + 1))
+        .min()
+        .unwrap_or(from_block)
+        .max(from_block);
+    if start_block > from_block {
+        println!(
+            "[{}] Resuming from block {}",
+            config.source_name(),
+            start_block
+        );
+    }
+
     let provider = factory.provider()?;
+    let best_block = provider.best_block_number()?;
+    let to_block = match config.to_block {
+        // Blocks the reth node has not synced yet can not be indexed
+        Some(to_block) if to_block > best_block + 1 => {
+            log::warn!(
+                "toBlockNumber {} is beyond the reth database, syncing up to block {}",
+                to_block,
+                best_block
+            );
+            best_block + 1
+        }
+        Some(to_block) => to_block,
+        // Without `toBlockNumber` the historical sync runs up to the current tip and then follows it
+        None => confirmed_tip(&provider, config.confirmations)? + 1,
+    };
+    drop(provider);
 
-    println!("MongoDB Syncing...");
+    let mut writer = Writer::new(
+        &mongodb,
+        &config.event_mappings,
+        checkpoints,
+        start_block,
+        Duration::from_millis(config.flush_interval_ms),
+        chain_id,
+    )
+    .await?;
+    let mut window = BlockWindow::new(config.reorg_window);
+
+    println!("[{}] MongoDB Syncing...", config.source_name());
     let start = Instant::now();
-    let bar = ProgressBar::new(to_block - from_block).with_style(
-        ProgressStyle::default_bar()
-            .template("{percent_precise}% [{bar:40}] {pos}/{len}")
-            .unwrap(),
+    let bar = progress.add(
+        ProgressBar::new(to_block.saturating_sub(from_block))
+            .with_style(
+                ProgressStyle::default_bar()
+                    .template("{prefix} {percent_precise}% [{bar:40}] {pos}/{len}")
+                    .unwrap(),
+            )
+            .with_prefix(config.source_name().to_string()),
+    );
+    bar.inc(start_block.saturating_sub(from_block));
+    let completed = sync_range(
+        &factory,
+        &config,
+        &mut writer,
+        &mut window,
+        start_block,
+        to_block,
+        &bar,
+    )
+    .await?;
+    writer.flush().await?;
+    bar.finish();
+    let duration = start.elapsed();
+    println!(
+        "[{}] Sync is done. Time taken: {:.2}",
+        config.source_name(),
+        duration.as_secs_f32()
+    );
+    println!("[{}] Writer: {}", config.source_name(), writer.stats());
+    for discovery in writer.discovered().discoveries() {
+        println!(
+            "Discovered {} contracts from {}.{}",
+            writer.discovered().len(&discovery),
+            discovery.collection_name,
+            discovery.parameter
+        );
+    }
+
+    if !completed {
+        println!(
+            "[{}] Sync was interrupted, it will resume from the last checkpoint",
+            config.source_name()
+        );
+        return Ok(());
+    }
+
+    if config.to_block.is_none() {
+        follow_tip(
+            &factory,
+            &config,
+            &mut writer,
+            &mut window,
+            to_block.max(start_block),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Indexes `from_block..to_block` with `config.workers` parallel workers.
+/// Every worker processes chunks of `config.chunk_size` blocks with its own read-only provider
+/// and sends the results through a bounded channel to `writer`.
+/// Returns false if the sync was interrupted with Ctrl-C.
+async fn sync_range<N: ProviderNodeTypes<ChainSpec = ChainSpec>>(
+    factory: &ProviderFactory<N>,
+    config: &Arc<IndexerConfig>,
+    writer: &mut Writer<'_>,
+    window: &mut BlockWindow,
+    from_block: u64,
+    to_block: u64,
+    bar: &ProgressBar,
+) -> eyre::Result<bool> {
+    let (sender, mut receiver) = mpsc::channel(WRITE_QUEUE_SIZE);
+    let chunks = Arc::new(ChunkQueue {
+        next_chunk: AtomicU64::new(from_block),
+        to_block,
+        chunk_size: config.chunk_size.max(1),
+    });
+    let checkpoints = Arc::new(writer.checkpoints().to_vec());
+
+    // A child contract has to be discovered before the blocks after its creation are processed
+    let workers = if writer.discovered().is_empty() {
+        config.workers.max(1)
+    } else {
+        if config.workers > 1 {
+            log::warn!("discoverAddressesFrom is used, syncing with a single worker");
+        }
+        1
+    };
+
+    let workers: Vec<_> = (0..workers)
+        .map(|_| {
+            let factory = factory.clone();
+            let config = config.clone();
+            let checkpoints = checkpoints.clone();
+            let discovered = writer.discovered().clone();
+            let chunks = chunks.clone();
+            let sender = sender.clone();
+            let bar = bar.clone();
+            tokio::task::spawn_blocking(move || {
+                run_worker(
+                    factory,
+                    config,
+                    checkpoints,
+                    discovered,
+                    chunks,
+                    sender,
+                    bar,
+                )
+            })
+        })
+        .collect();
+    // Only the workers hold senders now, so the channel closes once all of them are done
+    drop(sender);
+
+    loop {
+        let block = tokio::select! {
+            block = receiver.recv() => match block {
+                Some(block) => block?,
+                None => break,
+            },
+            _ = shutdown() => return Ok(false),
+        };
+
+        // Seed the reorg window with the last blocks before switching to following the tip
+        if block.block_number + config.reorg_window as u64 >= to_block {
+            window.push(block.block_number, block.block_hash);
+        }
+
+        writer.write(block).await?;
+        if writer.stats_due() {
+            bar.println(format!(
+                "[{}] Writer: {}",
+                config.source_name(),
+                writer.stats()
+            ));
+        }
+    }
+
+    for worker in workers {
+        worker.await?;
+    }
+    Ok(true)
+}
+
+/// The blocks of the historical range that no worker has taken yet.
+struct ChunkQueue {
+    next_chunk: AtomicU64,
+    to_block: u64,
+    chunk_size: u64,
+}
+
+impl ChunkQueue {
+    /// Takes the next chunk of blocks, `None` once the range is exhausted.
+    fn take(&self) -> Option<Range<u64>> {
+        let chunk_start = self
+            .next_chunk
+            .fetch_add(self.chunk_size, Ordering::Relaxed);
+        (chunk_start < self.to_block)
+            .then(|| chunk_start..(chunk_start + self.chunk_size).min(self.to_block))
+    }
+}
+
+/// Takes chunks of blocks until the range is exhausted and sends every processed block
+/// to the writer. Stops at the first error, after sending it.
+fn run_worker<N: ProviderNodeTypes<ChainSpec = ChainSpec>>(
+    factory: ProviderFactory<N>,
+    config: Arc<IndexerConfig>,
+    checkpoints: Arc<Vec<Option<u64>>>,
+    discovered: Arc<DiscoveredAddresses>,
+    chunks: Arc<ChunkQueue>,
+    sender: mpsc::Sender<eyre::Result<BlockWrites>>,
+    bar: ProgressBar,
+) {
+    while let Some(chunk) = chunks.take() {
+        let provider = match factory.provider() {
+            Ok(provider) => provider,
+            Err(e) => {
+                let _ = sender.blocking_send(Err(e.into()));
+                return;
+            }
+        };
+
+        for block_number in chunk {
+            let block = collect_block(
+                &factory,
+                &provider,
+                &config,
+                &checkpoints,
+                &discovered,
+                block_number,
+            );
+            bar.inc(1);
+
+            let failed = block.is_err();
+            // The writer stopped, most likely because of an error on its side
+            if sender.blocking_send(block).is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+/// Keeps indexing new canonical blocks as the reth node persists them,
+/// rolling back already indexed blocks that got reorged out.
+/// Runs until an error occurs or the indexer is stopped with Ctrl-C.
+async fn follow_tip<N: ProviderNodeTypes<ChainSpec = ChainSpec>>(
+    factory: &ProviderFactory<N>,
+    config: &IndexerConfig,
+    writer: &mut Writer<'_>,
+    window: &mut BlockWindow,
+    mut next_block: u64,
+) -> eyre::Result<()> {
+    println!(
+        "[{}] Following the chain tip from block {} (confirmations: {}, poll interval: {}ms)",
+        config.source_name(),
+        next_block,
+        config.confirmations,
+        config.poll_interval_ms
+    );
+    let poll_interval = Duration::from_millis(config.poll_interval_ms);
+
+    loop {
+        if writer.stats_due() {
+            println!("[{}] Writer: {}", config.source_name(), writer.stats());
+        }
+
+        // A new read transaction is needed to see the blocks written since the last poll
+        let provider = factory.provider()?;
+
+        if let Some(fork_block) = window.find_reorg(&provider)? {
+            let orphaned_blocks = window
+                .split_off(fork_block)
+                .into_iter()
+                .map(|(block_number, block_hash)| {
+                    Ok((block_number, block_hash, provider.block_hash(block_number)?))
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            log::warn!(
+                "Reorg detected at block {}, {} indexed blocks are no longer canonical",
+                fork_block,
+                orphaned_blocks.len()
+            );
+
+            let deleted = writer.rollback(fork_block, &orphaned_blocks).await?;
+            println!(
+                "[{}] Rolled back reorg at block {}, deleted {} documents",
+                config.source_name(),
+                fork_block,
+                deleted
+            );
+            next_block = next_block.min(fork_block);
+        }
+
+        let target_block = confirmed_tip(&provider, config.confirmations)?;
+
+        if next_block > target_block {
+            drop(provider);
+            writer.flush_if_due().await?;
+            tokio::select! {
+                _ = tokio::time::sleep(poll_interval) => continue,
+                _ = shutdown() => break,
+            }
+        }
+
+        for block_number in next_block..=target_block {
+            // Catching up can take long, stop between blocks
+            if shutdown_requested() {
+                break;
+            }
+            let block = collect_block(
+                factory,
+                &provider,
+                config,
+                writer.checkpoints(),
+                writer.discovered(),
+                block_number,
+            )?;
+            window.push(block_number, block.block_hash);
+            writer.write(block).await?;
+            next_block = block_number + 1;
+        }
+        if shutdown_requested() {
+            break;
+        }
+        info!("Indexed blocks up to {}", target_block);
+    }
+
+    writer.flush().await?;
+    println!(
+        "[{}] Stopped following the chain tip. Writer: {}",
+        config.source_name(),
+        writer.stats()
     );
-    for block_number in from_block..to_block {
-        info!("Checking block {}", block_number);
-        bar.inc(1);
-        match provider.header_by_number(block_number).unwrap() {
-            None => {
-                log::warn!("Block {} not found", block_number);
+    Ok(())
+}
+
+/// Resolves the `chain` config to a known chain spec, or builds one from a genesis JSON file.
+fn load_chain_spec(chain: &str) -> eyre::Result<Arc<ChainSpec>> {
+    let spec = match chain {
+        "mainnet" => MAINNET.clone(),
+        "sepolia" => SEPOLIA.clone(),
+        "holesky" => HOLESKY.clone(),
+        "dev" => DEV.clone(),
+        genesis_path => {
+            let content = std::fs::read_to_string(genesis_path)
+                .map_err(|e| eyre::eyre!("Failed to read genesis file {}: {}", genesis_path, e))?;
+            let genesis: Genesis = serde_json::from_str(&content)?;
+            Arc::new(genesis.into())
+        }
+    };
+    Ok(spec)
+}
+
+/// Returns the highest fully persisted block of the reth node, minus the confirmation depth.
+fn confirmed_tip<T: BlockNumReader>(provider: &T, confirmations: u64) -> eyre::Result<u64> {
+    Ok(provider.best_block_number()?.saturating_sub(confirmations))
+}
+
+/// Processes a single block for every mapping that has not indexed it yet.
+fn collect_block<N, T>(
+    factory: &ProviderFactory<N>,
+    provider: &T,
+    config: &IndexerConfig,
+    checkpoints: &[Option<u64>],
+    discovered: &DiscoveredAddresses,
+    block_number: u64,
+) -> eyre::Result<BlockWrites>
+where
+    N: ProviderNodeTypes<ChainSpec = ChainSpec>,
+    T: ReceiptProvider
+        + HeaderProvider
+        + BlockReader
+        + TransactionsProvider
+        + ChangeSetReader
+        + StorageReader,
+{
+    info!("Checking block {}", block_number);
+
+    // A block without a header was never indexed, it must not move the checkpoints past it
+    let block_header = provider
+        .header_by_number(block_number)?
+        .ok_or_else(|| eyre::eyre!("Block {} not found", block_number))?;
+    let block_hash = block_header.hash_slow();
+    let mut writes = BlockWrites::new(block_number, block_hash);
+
+    for (mapping, checkpoint) in config.event_mappings.iter().zip(checkpoints) {
+        // Already indexed by a previous run
+        if checkpoint.is_some_and(|checkpoint| checkpoint >= block_number) {
+            continue;
+        }
+
+        // Plain ETH transfers leave no trace in the bloom, every block has to be checked
+        if let Some(native_transfers) = &mapping.native_transfers {
+            process_native_transfers(
+                provider,
+                native_transfers,
+                &block_header,
+                block_hash,
+                &mut writes,
+            )?;
+        }
+
+        // Changesets are per block, not per log
+        if let Some(state_changes) = &mapping.state_changes {
+            process_state_changes(
+                factory,
+                provider,
+                state_changes,
+                &block_header,
+                block_hash,
+                &mut writes,
+            )?;
+        }
+
+        if let Some(deployments) = &mapping.contract_deployments {
+            process_contract_deployments(
+                factory,
+                provider,
+                deployments,
+                &block_header,
+                block_hash,
+                &mut writes,
+            )?;
+        }
+
+        // Calldata is not in the bloom either
+        if !mapping.decode_function_items.is_empty() {
+            process_function_calls(
+                provider,
+                mapping,
+                discovered,
+                &block_header,
+                block_hash,
+                &mut writes,
+            )?;
+        }
+
+        // If the event needs to be filtered by a specific contract address.
+        // Discovered children can be too many to check against the bloom,
+        // their logs are matched on the receipts instead.
+        if let (Some(contract_addr), None) = (
+            &mapping.filter_by_contract_addresses,
+            &mapping.discover_addresses_from,
+        ) {
+            if !contract_addr
+                .iter()
+                .any(|address| contract_in_bloom(*address, block_header.logs_bloom))
+            {
                 continue;
             }
-            Some(block_header) => {
-                for mapping in &config.event_mappings {
-                    // If the event needs to be filtered by a specific contract address
-                    if let Some(contract_addr) = &mapping.filter_by_contract_addresses {
-                        if !contract_addr
-                            .iter()
-                            .any(|address| contract_in_bloom(*address, block_header.logs_bloom))
-                        {
-                            continue;
-                        }
-                    }
-
-                    if !mapping.decode_abi_items.iter().any(|abi_item| {
-                        topic_in_bloom(abi_item_topic_id(abi_item), block_header.logs_bloom)
-                    }) {
-                        continue;
-                    }
-
-                    process_block(&provider, &mongodb, &mapping, &block_header, block_number).await;
+        }
+
+        if !mapping
+            .decode_abi_items
+            .iter()
+            .any(|abi_item| abi_item_in_bloom(abi_item, block_header.logs_bloom))
+        {
+            continue;
+        }
+
+        process_block(
+            provider,
+            mapping,
+            discovered,
+            &block_header,
+            block_hash,
+            &mut writes,
+        )?;
+        // Children created in this block are already indexed by the mappings listed after it
+        discovered.discover(&writes);
+    }
+
+    for mapping in &config.event_mappings {
+        enrich_documents(factory, mapping, &block_header, &mut writes)?;
+    }
+
+    Ok(writes)
+}
+
+/// Reads the transactions of a block with their receipts.
+/// Yields the position of every transaction within the block, the transaction, its receipt
+/// and the gas it used. The header exists, so missing data means the reth database can not
+/// be read right now; skipping it would leave a permanent gap behind the checkpoint.
+fn block_transactions<T: ReceiptProvider + BlockReader + TransactionsProvider>(
+    provider: &T,
+    block_number: u64,
+) -> eyre::Result<Vec<(u64, TransactionSignedNoHash, Receipt, u64)>> {
+    let block_indices = provider
+        .block_body_indices(block_number)?
+        .ok_or_else(|| eyre::eyre!("No body indices for block {}", block_number))?;
+    let transactions = provider.transactions_by_tx_range(block_indices.tx_num_range())?;
+    let receipts = provider.receipts_by_tx_range(block_indices.tx_num_range())?;
+    if transactions.len() as u64 != block_indices.tx_count
+        || receipts.len() as u64 != block_indices.tx_count
+    {
+        return Err(eyre::eyre!(
+            "Block {} has {} transactions, found {} transactions and {} receipts",
+            block_number,
+            block_indices.tx_count,
+            transactions.len(),
+            receipts.len()
+        ));
+    }
+
+    // Receipts only store the gas used by the block up to and including the transaction
+    let mut previous_gas_used = 0;
+    Ok(transactions
+        .into_iter()
+        .zip(receipts)
+        .enumerate()
+        .map(|(tx_index, (tx, receipt))| {
+            let gas_used = receipt.cumulative_gas_used - previous_gas_used;
+            previous_gas_used = receipt.cumulative_gas_used;
+            (tx_index as u64, tx, receipt, gas_used)
+        })
+        .collect())
+}
+
+/// Decodes the calls of a block to the indexed contracts whose selector matches a function item.
+fn process_function_calls<T: ReceiptProvider + BlockReader + TransactionsProvider>(
+    provider: &T,
+    mapping: &IndexerContractMapping,
+    discovered: &DiscoveredAddresses,
+    header: &Header,
+    block_hash: B256,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    for (tx_index, tx, receipt, gas_used) in block_transactions(provider, header.number)? {
+        match tx.transaction.kind().to() {
+            Some(to) if indexes_contract(mapping, discovered, to) => {}
+            _ => continue,
+        }
+
+        let input = tx.transaction.input();
+        let Some(abi_item) = mapping
+            .decode_function_items
+            .iter()
+            .find(|abi_item| input.starts_with(&abi_item.selector()))
+        else {
+            continue;
+        };
+
+        let context = tx_context(
+            &mapping.include_tx_fields,
+            header,
+            &tx,
+            tx_index,
+            0,
+            &receipt,
+            gas_used,
+        );
+
+        match decode_call(&input[4..], abi_item) {
+            Ok(Some(arguments)) => writes.add(
+                &abi_item.collection_name,
+                vec![call_document(
+                    header,
+                    block_hash,
+                    &tx,
+                    &context,
+                    abi_item,
+                    receipt.success,
+                    arguments,
+                )],
+            ),
+            // The arguments do not match the filters of the inputs
+            Ok(None) => {}
+            Err(error) => match abi_item.on_decode_error {
+                DecodeErrorPolicy::Skip => log::warn!(
+                    "Skipping {} call {} in block {}: {}",
+                    abi_item.name,
+                    tx.hash(),
+                    header.number,
+                    error
+                ),
+                DecodeErrorPolicy::Fail => {
+                    return Err(eyre::eyre!(
+                        "Failed to decode {} call {} in block {}: {}",
+                        abi_item.name,
+                        tx.hash(),
+                        header.number,
+                        error
+                    ))
                 }
+                DecodeErrorPolicy::Store => writes.add(
+                    DEAD_LETTERS_COLLECTION,
+                    vec![dead_letter_call_document(
+                        header, block_hash, &tx, &context, abi_item, &error,
+                    )],
+                ),
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Indexes the changes of the tracked accounts and storage slots within a block.
+/// The changesets tell which accounts and slots changed, the values after the block
+/// are read from the historical state.
+fn process_state_changes<N: ProviderNodeTypes, T: ChangeSetReader + StorageReader>(
+    factory: &ProviderFactory<N>,
+    provider: &T,
+    state_changes: &StateChanges,
+    header: &Header,
+    block_hash: B256,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    let block_number = header.number;
+    let changed_accounts: Vec<_> = provider
+        .account_block_changeset(block_number)?
+        .into_iter()
+        .filter(|change| state_changes.addresses.contains(&change.address))
+        .collect();
+    let changed_slots: Vec<(Address, B256)> = match &state_changes.storage_slots {
+        Some(storage_slots) => provider
+            .changed_storages_with_range(block_number..=block_number)?
+            .into_iter()
+            .filter(|(address, _)| state_changes.addresses.contains(address))
+            .flat_map(|(address, slots)| slots.into_iter().map(move |slot| (address, slot)))
+            .filter(|(_, slot)| storage_slots.contains(slot))
+            .collect(),
+        None => Vec::new(),
+    };
+    if changed_accounts.is_empty() && changed_slots.is_empty() {
+        return Ok(());
+    }
+
+    let state_after = factory.history_by_block_number(block_number)?;
+    let mut documents = Vec::new();
+    for change in changed_accounts {
+        let after = state_after.basic_account(change.address)?;
+        // Storage-only changes of a contract leave the balance and nonce untouched
+        if change.info.map(|info| (info.balance, info.nonce))
+            == after.map(|after| (after.balance, after.nonce))
+        {
+            continue;
+        }
+        documents.push(account_change_document(
+            header,
+            block_hash,
+            change.address,
+            change.info,
+            after,
+        ));
+    }
+
+    if !changed_slots.is_empty() {
+        // The storage changeset only lists the changed slots, the state before the block
+        // holds their previous values
+        let state_before = factory.history_by_block_number(block_number.saturating_sub(1))?;
+        for (address, slot) in changed_slots {
+            let before = state_before.storage(address, slot)?.unwrap_or_default();
+            let after = state_after.storage(address, slot)?.unwrap_or_default();
+            if before != after {
+                documents.push(storage_change_document(
+                    header, block_hash, address, slot, before, after,
+                ));
             }
         }
     }
-    bar.finish();
-    let duration = start.elapsed();
-    println!("Sync is done. Time taken: {:.2}", duration.as_secs_f32());
 
+    if !documents.is_empty() {
+        writes.add(&state_changes.collection_name, documents);
+    }
+    Ok(())
+}
+
+/// Indexes the contracts deployed by the contract creation transactions of a block.
+/// Contracts created by other contracts during execution are not seen.
+fn process_contract_deployments<
+    N: ProviderNodeTypes,
+    T: ReceiptProvider + BlockReader + TransactionsProvider,
+>(
+    factory: &ProviderFactory<N>,
+    provider: &T,
+    deployments: &ContractDeployments,
+    header: &Header,
+    block_hash: B256,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    let mut documents = Vec::new();
+    for (tx_index, tx, receipt, _) in block_transactions(provider, header.number)? {
+        // A reverted creation deploys nothing
+        if !tx.transaction.is_create() || !receipt.success {
+            continue;
+        }
+
+        let Some(deployer) = tx.recover_signer() else {
+            log::warn!(
+                "Could not recover the deployer of tx {} in block {}",
+                tx.hash(),
+                header.number
+            );
+            continue;
+        };
+        if let Some(deployers) = &deployments.deployers {
+            if !deployers.contains(&deployer) {
+                continue;
+            }
+        }
+
+        let contract_address = deployer.create(tx.transaction.nonce());
+        let mut document = deployment_document(
+            header,
+            block_hash,
+            &tx,
+            tx_index,
+            deployer,
+            contract_address,
+        );
+
+        if let Some(code_hashes) = &deployments.code_hashes {
+            // The state after the deployment block, independent of later self-destructs
+            let code_hash = factory
+                .history_by_block_number(header.number)?
+                .basic_account(contract_address)?
+                .and_then(|account| account.bytecode_hash);
+            document.insert("code_hash", code_hash.map(|hash| hash.to_string()));
+            document.insert(
+                "code_hash_matches",
+                code_hash.is_some_and(|hash| code_hashes.contains(&hash)),
+            );
+        }
+
+        documents.push(document);
+    }
+
+    if !documents.is_empty() {
+        writes.add(&deployments.collection_name, documents);
+    }
+    Ok(())
+}
+
+/// Indexes the transactions of a block that send ETH to or from the configured addresses.
+fn process_native_transfers<T: ReceiptProvider + BlockReader + TransactionsProvider>(
+    provider: &T,
+    native_transfers: &NativeTransfers,
+    header: &Header,
+    block_hash: B256,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    let mut documents = Vec::new();
+    for (tx_index, tx, receipt, gas_used) in block_transactions(provider, header.number)? {
+        if tx.transaction.value().is_zero() {
+            continue;
+        }
+
+        if let Some(to_addresses) = &native_transfers.to_addresses {
+            match tx.transaction.kind().to() {
+                Some(to) if to_addresses.contains(to) => {}
+                _ => continue,
+            }
+        }
+
+        // Recovering the sender is expensive, so it is only done for transfers
+        let Some(from) = tx.recover_signer() else {
+            log::warn!(
+                "Could not recover the sender of tx {} in block {}",
+                tx.hash(),
+                header.number
+            );
+            continue;
+        };
+        if let Some(from_addresses) = &native_transfers.from_addresses {
+            if !from_addresses.contains(&from) {
+                continue;
+            }
+        }
+
+        documents.push(native_transfer_document(
+            header, block_hash, &tx, tx_index, from, &receipt, gas_used,
+        ));
+    }
+
+    if !documents.is_empty() {
+        writes.add(&native_transfers.collection_name, documents);
+    }
     Ok(())
 }
 
@@ -141,102 +1028,142 @@ fn topic_in_bloom(topic: B256, logs_bloom: Bloom) -> bool {
     FilteredParams::matches_topics(logs_bloom, &topic_filter)
 }
 
-fn abi_item_topic_id(item: &ABIItem) -> B256 {
-    let input_types: Vec<String> = item
-        .inputs
-        .iter()
-        .map(|input| input.type_.clone())
-        .collect();
+/// Checks the signature topic and the filtered indexed inputs of an ABI item against the bloom.
+/// Anonymous events have no signature topic, only their indexed inputs can be checked.
+fn abi_item_in_bloom(abi_item: &ABIItem, logs_bloom: Bloom) -> bool {
+    if !abi_item.anonymous && !topic_in_bloom(abi_item.topic_id(), logs_bloom) {
+        return false;
+    }
+
+    abi_item.topic_filters.iter().flatten().all(|allowed| {
+        allowed
+            .iter()
+            .any(|topic| topic_in_bloom(*topic, logs_bloom))
+    })
+}
 
-    keccak256(format!("{}({})", item.name, input_types.join(",")))
+/// Whether a mapping indexes the logs of a contract,
+/// by its address filter and the children discovered from its factory.
+fn indexes_contract(
+    mapping: &IndexerContractMapping,
+    discovered: &DiscoveredAddresses,
+    address: &Address,
+) -> bool {
+    let discovered_child = mapping
+        .discover_addresses_from
+        .as_ref()
+        .is_some_and(|discovery| discovered.contains(discovery, address));
+
+    match &mapping.filter_by_contract_addresses {
+        Some(contract_addresses) => discovered_child || contract_addresses.contains(address),
+        None => discovered_child || mapping.discover_addresses_from.is_none(),
+    }
 }
 
-async fn process_block<T: ReceiptProvider + HeaderProvider + BlockReader + TransactionsProvider>(
+/// Indexes the logs of a single block for the given mapping into `writes`.
+fn process_block<T: ReceiptProvider + HeaderProvider + BlockReader + TransactionsProvider>(
     provider: &T,
-    mongodb: &Database,
     mapping: &IndexerContractMapping,
+    discovered: &DiscoveredAddresses,
     header: &Header,
-    block_number: u64,
-) {
-    let block_indecies = provider
-        .block_body_indices(block_number)
-        .unwrap_or_else(|e| {
-            eprintln!("Error fetching block {}: {}", block_number, e);
-            // panic!("Failed to fetch block indices");
-            return None;
-        });
+    block_hash: B256,
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
+    // Position of the next log within the block, counting the logs of every transaction
+    let mut block_log_index = 0;
+    for (tx_index, tx, receipt, gas_used) in block_transactions(provider, header.number)? {
+        let first_log_index = block_log_index;
+        block_log_index += receipt.logs.len() as u64;
 
-    if let Some(block_indecies) = block_indecies {
-        for tx_id in
-            block_indecies.first_tx_num..block_indecies.first_tx_num + block_indecies.tx_count
-        {
-            let receipt = match provider.receipt(tx_id) {
-                Ok(Some(receipt)) => receipt,
-                _ => continue,
-            };
+        let logs: Vec<(u64, Log)> = receipt
+            .logs
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, log)| (first_log_index + i as u64, log))
+            .filter(|(_, log)| indexes_contract(mapping, discovered, &log.address))
+            .collect();
 
-            let logs: Vec<Log> =
-                if let Some(contract_addresses) = &mapping.filter_by_contract_addresses {
-                    receipt
-                        .logs
-                        .iter()
-                        .filter(|log| {
-                            contract_addresses
-                                .iter()
-                                .any(|address| address == &log.address)
-                        })
-                        .cloned()
-                        .collect()
-                } else {
-                    receipt.logs
-                };
-
-            if logs.is_empty() {
-                continue;
-            }
-
-            process_tx(provider, mongodb, mapping, header, tx_id, &logs).await;
+        if logs.is_empty() {
+            continue;
         }
+
+        let context = tx_context(
+            &mapping.include_tx_fields,
+            header,
+            &tx,
+            tx_index,
+            first_log_index,
+            &receipt,
+            gas_used,
+        );
+        process_tx(mapping, header, block_hash, &tx, &context, &logs, writes)?;
     }
+
+    Ok(())
 }
 
-async fn process_tx<T: ReceiptProvider + HeaderProvider + BlockReader + TransactionsProvider>(
-    provider: &T,
-    mongodb: &Database,
+fn process_tx(
     mapping: &IndexerContractMapping,
     header: &Header,
-    tx_id: u64,
-    logs: &[Log],
-) {
-    let tx = match provider.transaction_by_id_no_hash(tx_id) {
-        Ok(Some(tx)) => tx,
-        _ => return,
-    };
-
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    context: &TxContext,
+    logs: &[(u64, Log)],
+    writes: &mut BlockWrites,
+) -> eyre::Result<()> {
     for abi_item in &mapping.decode_abi_items {
-        let topic_id = abi_item_topic_id(abi_item);
-        if !topic_in_bloom(topic_id, header.logs_bloom) {
+        if !abi_item_in_bloom(abi_item, header.logs_bloom) {
             continue;
         }
 
-        let decoded_logs = decode_logs(topic_id, logs, abi_item);
-        if decoded_logs.is_empty() {
-            continue;
+        let mut decoded_logs = Vec::new();
+        let mut failures = Vec::new();
+        for result in decode_logs(logs, abi_item, &mapping.decode_abi_items) {
+            let failure = match result {
+                Ok(decoded_log) => {
+                    decoded_logs.push(decoded_log);
+                    continue;
+                }
+                Err(failure) => failure,
+            };
+
+            match abi_item.on_decode_error {
+                DecodeErrorPolicy::Skip => log::warn!(
+                    "Skipping {} log {} in block {}: {}",
+                    abi_item.name,
+                    failure.log_index,
+                    header.number,
+                    failure.error
+                ),
+                DecodeErrorPolicy::Fail => {
+                    return Err(eyre::eyre!(
+                        "Failed to decode {} log {} in block {}: {}",
+                        abi_item.name,
+                        failure.log_index,
+                        header.number,
+                        failure.error
+                    ))
+                }
+                DecodeErrorPolicy::Store => failures.push(failure),
+            }
         }
 
-        match insert_logs(
-            mongodb,
-            &abi_item.collection_name,
-            header,
-            &tx,
-            &decoded_logs,
-        )
-        .await
-        {
-            Ok(_) => (),
-            Err(e) => log::error!("Error inserting logs: {}", e),
+        if !decoded_logs.is_empty() {
+            writes.add(
+                &abi_item.collection_name,
+                log_documents(header, block_hash, tx, context, &decoded_logs),
+            );
+        }
+        if !failures.is_empty() {
+            writes.add(
+                DEAD_LETTERS_COLLECTION,
+                dead_letter_documents(header, block_hash, tx, context, abi_item, &failures),
+            );
         }
     }
+
+    Ok(())
 }
 
 // fn sync_events<T: ReceiptProvider + HeaderProvider + BlockReader + TransactionsProvider>(
@@ -305,3 +1232,91 @@ async fn process_tx<T: ReceiptProvider + HeaderProvider + BlockReader + Transact
 
 //     Ok(())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::mongodb::bson::doc;
+    use alloy::primitives::{address, keccak256, Bytes};
+    use serde_json::json;
+
+    const PAIR: Address = address!("b4e16d0168e52d35cacd2c6185b44281ec28c9dc");
+
+    fn swap(to_filter: serde_json::Value) -> ABIItem {
+        let mut swap: ABIItem = serde_json::from_value(json!({
+            "name": "Swap",
+            "collectionName": "swaps",
+            "inputs": [
+                { "name": "sender", "type": "address", "indexed": true },
+                { "name": "amount0In", "type": "uint256" },
+                { "name": "to", "type": "address", "indexed": true, "rethFilter": to_filter },
+            ],
+        }))
+        .unwrap();
+        swap.resolve_topic_filters().unwrap();
+        swap
+    }
+
+    fn bloom(topics: Vec<B256>) -> Bloom {
+        let mut bloom = Bloom::default();
+        bloom.accrue_log(&Log::new_unchecked(PAIR, topics, Bytes::new()));
+        bloom
+    }
+
+    #[test]
+    fn blocks_without_the_filtered_topics_are_skipped() {
+        let recipient = address!("7a250d5630b4cf539739df2c5dacb4c659f2488d");
+        let other = address!("def1c0ded9bec7f1a1670819833240f027b25eff");
+        let filtered = swap(json!({ "equals": recipient.to_string() }));
+        let swap_topics = |to: Address| {
+            vec![
+                filtered.topic_id(),
+                B256::left_padding_from(&[1; 20]),
+                to.into_word(),
+            ]
+        };
+
+        assert!(abi_item_in_bloom(&filtered, bloom(swap_topics(recipient))));
+        assert!(!abi_item_in_bloom(&filtered, bloom(swap_topics(other))));
+
+        // Without a filter on `to` only the signature topic has to be in the bloom
+        let unfiltered = swap(json!(null));
+        assert!(abi_item_in_bloom(&unfiltered, bloom(swap_topics(other))));
+        assert!(!abi_item_in_bloom(
+            &unfiltered,
+            bloom(vec![keccak256("Sync(uint112,uint112)")])
+        ));
+    }
+
+    #[test]
+    fn children_are_indexed_until_their_creation_is_rolled_back() {
+        let factory = address!("1f98431c8ad98523631ae4a59f267346ea31f984");
+        let pool = address!("88e6a0c2ddd26feeb64f039a2c41296fcb3f5640");
+        let pools: IndexerContractMapping = serde_json::from_value(json!({
+            "filterByContractAddress": [PAIR],
+            "discoverAddressesFrom": { "collectionName": "pool-created", "parameter": "pool" },
+        }))
+        .unwrap();
+        let discovered = DiscoveredAddresses::new(std::slice::from_ref(&pools));
+        let children_only = IndexerContractMapping {
+            filter_by_contract_addresses: None,
+            ..pools.clone()
+        };
+
+        assert!(indexes_contract(&pools, &discovered, &PAIR));
+        assert!(!indexes_contract(&pools, &discovered, &pool));
+        assert!(!indexes_contract(&children_only, &discovered, &pool));
+
+        let mut writes = BlockWrites::new(100, B256::ZERO);
+        writes.add("pool-created", vec![doc! { "pool": pool.to_string() }]);
+        discovered.discover(&writes);
+        assert!(indexes_contract(&pools, &discovered, &pool));
+        assert!(indexes_contract(&children_only, &discovered, &pool));
+        assert!(!indexes_contract(&children_only, &discovered, &factory));
+
+        discovered.rollback(100);
+        assert!(!indexes_contract(&pools, &discovered, &pool));
+        assert!(!indexes_contract(&children_only, &discovered, &pool));
+        assert!(indexes_contract(&pools, &discovered, &PAIR));
+    }
+}
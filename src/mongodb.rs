@@ -1,54 +1,102 @@
+use alloy::primitives::{keccak256, Address, B256, U256};
 use mongodb::{
-    bson::{self, doc, DateTime, Document},
-    options::{ClientOptions, ResolverConfig},
-    Client, Collection, Database, IndexModel,
+    bson::{self, doc, Bson, DateTime, Document},
+    error::ErrorKind,
+    options::{ClientOptions, IndexOptions, ResolverConfig},
+    Client, ClientSession, Collection, Database, IndexModel,
 };
-use reth_primitives::{Header, TransactionSigned, TransactionSignedNoHash};
+use reth_primitives::{Account, Header, Receipt, TransactionSigned, TransactionSignedNoHash};
+use std::collections::HashSet;
+use std::str::FromStr;
 
 use crate::{
-    config::{IndexerContractMapping, IndexerMongoDBConfig},
-    decoder::DecodedLog,
+    config::{
+        ABIItem, AddressDiscovery, IndexerConfig, IndexerContractMapping, IndexerMongoDBConfig,
+        TxField,
+    },
+    decoder::{numeric_to_bson, DecodeError, DecodeFailure, DecodedLog, DecodedTopic},
 };
 
-pub async fn init_mongodb(
-    config: &IndexerMongoDBConfig,
-    event_mappings: &[IndexerContractMapping],
-) -> eyre::Result<Database> {
+/// Connects to the database of a source.
+pub async fn init_mongodb(config: &IndexerMongoDBConfig) -> eyre::Result<Database> {
     let options = ClientOptions::parse(&config.connection_string).await?;
     let client = Client::with_options(options)?;
-    let db = client.database(&config.database);
-    // Need to create tables
-    create_collections(&db, config, event_mappings).await?;
-    Ok(db)
+    Ok(client.database(&config.database))
 }
 
-async fn create_collections(
-    db: &Database,
-    config: &IndexerMongoDBConfig,
-    event_mappings: &[IndexerContractMapping],
-) -> eyre::Result<()> {
-    for mapping in event_mappings {
-        for abi_item in &mapping.decode_abi_items {
-            let collection_name = &abi_item.collection_name;
+/// Collection that stores the last fully processed block of every event mapping.
+const CHECKPOINTS_COLLECTION: &str = "_reth_indexer_checkpoints";
 
-            if config.drop_tables {
-                println!("Dropping collection: {}", collection_name);
-                db.collection::<Document>(collection_name).drop().await?;
-            }
+/// Collection that keeps an audit log of every detected chain reorganization.
+const REORGS_COLLECTION: &str = "_reth_indexer_reorgs";
+
+/// Collection that stores the raw logs that could not be decoded.
+pub const DEAD_LETTERS_COLLECTION: &str = "_reth_indexer_dead_letters";
+/// Identifies a dead letter, a log can fail to decode as several ABI items.
+pub const DEAD_LETTER_KEY_FIELDS: &[&str] =
+    &["block_number", "tx_index", "log_index", "collection_name"];
+
+/// Server error code of creating a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
+/// Creates the collections and indexes of every source with the chain id of its reth database,
+/// before any source writes to them. Sources can share a database and its collections,
+/// so `dropTableBeforeSync` only removes what belongs to the chain of a source.
+pub async fn create_collections(sources: &[(&IndexerConfig, u64)]) -> eyre::Result<()> {
+    for (config, chain_id) in sources {
+        let db = init_mongodb(&config.mongodb).await?;
+        let collection_names = config
+            .event_mappings
+            .iter()
+            .flat_map(|mapping| mapping.collection_names());
+        for collection_name in collection_names {
+            backfill_chain_id(&db, sources, config, *chain_id, collection_name).await?;
+        }
+    }
+
+    // Every reset is done first, so no source drops collections another one just created
+    for (config, chain_id) in sources {
+        if config.mongodb.drop_tables {
+            let db = init_mongodb(&config.mongodb).await?;
+            drop_source_collections(&db, *chain_id, &config.event_mappings).await?;
+        }
+    }
+
+    for (config, _) in sources {
+        let db = init_mongodb(&config.mongodb).await?;
+
+        // Collections can not be created implicitly by the inserts of a transaction on older servers
+        create_collection(&db, DEAD_LETTERS_COLLECTION).await?;
+        create_unique_index(&db, DEAD_LETTERS_COLLECTION, DEAD_LETTER_KEY_FIELDS).await?;
+
+        for mapping in &config.event_mappings {
+            for collection_name in mapping.collection_names() {
+                // Collections are kept between runs so an interrupted sync can be resumed
+                create_collection(&db, collection_name).await?;
+                create_unique_index(
+                    &db,
+                    collection_name,
+                    unique_key_fields(mapping, collection_name),
+                )
+                .await?;
 
-            db.create_collection(collection_name).await?;
-            println!("Created collection: {}", collection_name);
-
-            if let Some(custom_db_indexes) = &abi_item.custom_db_indexes {
-                for index in custom_db_indexes {
-                    let index = IndexModel::builder()
-                        .keys(Document::from_iter(index.iter().map(|i| {
-                            (i.index_field.clone(), bson::Bson::Int32(i.sort_asc as i32))
-                        })))
-                        .build();
-                    db.collection::<Document>(collection_name)
-                        .create_index(index)
-                        .await?;
+                let custom_db_indexes = mapping
+                    .decode_abi_items
+                    .iter()
+                    .chain(&mapping.decode_function_items)
+                    .find(|abi_item| abi_item.collection_name == collection_name)
+                    .and_then(|abi_item| abi_item.custom_db_indexes.as_ref());
+                if let Some(custom_db_indexes) = custom_db_indexes {
+                    for index in custom_db_indexes {
+                        let index = IndexModel::builder()
+                            .keys(Document::from_iter(index.iter().map(|i| {
+                                (i.index_field.clone(), bson::Bson::Int32(i.sort_asc as i32))
+                            })))
+                            .build();
+                        db.collection::<Document>(collection_name)
+                            .create_index(index)
+                            .await?;
+                    }
                 }
             }
         }
@@ -57,41 +105,771 @@ async fn create_collections(
     Ok(())
 }
 
-pub async fn insert_logs(
+/// Stamps the documents written before `chain_id` was stored with the chain of the source
+/// writing their collection. If sources of several chains write it, the owner of these
+/// documents is unknown and they have to be stamped by indexing with a single source first.
+async fn backfill_chain_id(
     db: &Database,
+    sources: &[(&IndexerConfig, u64)],
+    config: &IndexerConfig,
+    chain_id: u64,
     collection_name: &str,
+) -> eyre::Result<()> {
+    let collection = db.collection::<Document>(collection_name);
+    let legacy_documents = doc! { "chain_id": { "$exists": false } };
+    if collection
+        .find_one(legacy_documents.clone())
+        .await?
+        .is_none()
+    {
+        return Ok(());
+    }
+
+    let other_chain = sources.iter().find(|(other, other_chain_id)| {
+        *other_chain_id != chain_id
+            && other.mongodb.connection_string == config.mongodb.connection_string
+            && other.mongodb.database == config.mongodb.database
+            && other
+                .event_mappings
+                .iter()
+                .any(|mapping| mapping.collection_names().contains(&collection_name))
+    });
+    if let Some((_, other_chain_id)) = other_chain {
+        return Err(eyre::eyre!(
+            "{} holds documents without chain_id written by an older version, chains {} and {} \
+             both write it, index them with a single source first",
+            collection_name,
+            chain_id,
+            other_chain_id
+        ));
+    }
+
+    let result = collection
+        .update_many(
+            legacy_documents,
+            doc! { "$set": { "chain_id": chain_id as i64 } },
+        )
+        .await?;
+    println!(
+        "Stamped {} documents of {} with chain id {}",
+        result.modified_count, collection_name, chain_id
+    );
+    Ok(())
+}
+
+/// Creates a collection, an existing one is kept as it is.
+async fn create_collection(db: &Database, collection_name: &str) -> eyre::Result<()> {
+    let Err(e) = db.create_collection(collection_name).await else {
+        println!("Created collection: {}", collection_name);
+        return Ok(());
+    };
+    match &*e.kind {
+        ErrorKind::Command(command) if command.code == NAMESPACE_EXISTS => {
+            println!("Collection already exists: {}", collection_name);
+            Ok(())
+        }
+        _ => Err(e.into()),
+    }
+}
+
+/// Removes what a chain indexed with the given mappings: its documents, dead letters and
+/// checkpoints. Collections that hold no documents of other chains are dropped.
+async fn drop_source_collections(
+    db: &Database,
+    chain_id: u64,
+    event_mappings: &[IndexerContractMapping],
+) -> eyre::Result<()> {
+    let collection_names: Vec<&str> = event_mappings
+        .iter()
+        .flat_map(|mapping| mapping.collection_names())
+        .collect();
+
+    for collection_name in &collection_names {
+        let collection = db.collection::<Document>(collection_name);
+        if collection
+            .find_one(doc! { "chain_id": { "$ne": chain_id as i64 } })
+            .await?
+            .is_some()
+        {
+            println!(
+                "Deleting the chain {} documents of collection: {}",
+                chain_id, collection_name
+            );
+            collection
+                .delete_many(doc! { "chain_id": chain_id as i64 })
+                .await?;
+        } else {
+            println!("Dropping collection: {}", collection_name);
+            collection.drop().await?;
+        }
+    }
+
+    db.collection::<Document>(DEAD_LETTERS_COLLECTION)
+        .delete_many(doc! {
+            "chain_id": chain_id as i64,
+            "collection_name": { "$in": collection_names },
+        })
+        .await?;
+    reset_checkpoints(db, chain_id, event_mappings).await
+}
+
+/// Creates the unique index on `chain_id` and `key_fields` that identifies the documents
+/// of a collection, since several chains can write the same collection.
+async fn create_unique_index(
+    db: &Database,
+    collection_name: &str,
+    key_fields: &[&str],
+) -> eyre::Result<()> {
+    let unique_keys = Document::from_iter(
+        ["chain_id"]
+            .iter()
+            .chain(key_fields)
+            .map(|field| (field.to_string(), Bson::Int32(1))),
+    );
+    let collection = db.collection::<Document>(collection_name);
+
+    let mut indexes = collection.list_indexes().await?;
+    while indexes.advance().await? {
+        if indexes.deserialize_current()?.keys == unique_keys {
+            return Ok(());
+        }
+    }
+
+    // Documents of versions that did not store the position of a log can not be told apart
+    if key_fields.contains(&"tx_index") {
+        let legacy_documents = collection
+            .count_documents(doc! { "tx_index": { "$exists": false } })
+            .await?;
+        if legacy_documents > 0 {
+            return Err(eyre::eyre!(
+                "{} holds {} documents without tx_index and log_index written by an older \
+                 version, set dropTableBeforeSync to index them again",
+                collection_name,
+                legacy_documents
+            ));
+        }
+    }
+
+    let unique_index = IndexModel::builder()
+        .keys(unique_keys)
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    collection.create_index(unique_index).await?;
+    Ok(())
+}
+
+/// The fields identifying a document of a chain, backed by the unique index of its collection
+/// together with `chain_id`. Logs are identified by their position within the block,
+/// other transaction documents by their transaction alone and state changes by their
+/// account and slot.
+pub fn unique_key_fields(
+    mapping: &IndexerContractMapping,
+    collection_name: &str,
+) -> &'static [&'static str] {
+    let is_state_changes = mapping
+        .state_changes
+        .as_ref()
+        .is_some_and(|state_changes| state_changes.collection_name == collection_name);
+    if is_state_changes {
+        &["block_number", "address", "slot"]
+    } else {
+        &["block_number", "tx_index", "log_index"]
+    }
+}
+
+/// Returns the last fully processed block of every mapping, in the same order as `event_mappings`.
+pub async fn load_checkpoints(
+    db: &Database,
+    chain_id: u64,
+    event_mappings: &[IndexerContractMapping],
+) -> eyre::Result<Vec<Option<u64>>> {
+    let collection: Collection<Document> = db.collection(CHECKPOINTS_COLLECTION);
+
+    let mut checkpoints = Vec::with_capacity(event_mappings.len());
+    for mapping in event_mappings {
+        let checkpoint = collection
+            .find_one(doc! { "_id": checkpoint_key(chain_id, mapping) })
+            .await?
+            .and_then(|checkpoint| checkpoint.get_i64("block_number").ok())
+            .map(|block_number| block_number as u64);
+        checkpoints.push(checkpoint);
+    }
+
+    Ok(checkpoints)
+}
+
+/// Returns the children a factory event created so far, with the block they were created in.
+/// They are read back from the decoded factory events, so no separate state has to be kept.
+pub async fn load_discovered_addresses(
+    db: &Database,
+    chain_id: u64,
+    discovery: &AddressDiscovery,
+) -> eyre::Result<Vec<(Address, u64)>> {
+    let mut cursor = db
+        .collection::<Document>(&discovery.collection_name)
+        .find(doc! {
+            &discovery.parameter: { "$type": "string" },
+            "chain_id": chain_id as i64,
+        })
+        .projection(doc! { "_id": 0, &discovery.parameter: 1, "block_number": 1 })
+        .await?;
+
+    let mut children = Vec::new();
+    while cursor.advance().await? {
+        let doc = cursor.deserialize_current()?;
+        let address = doc.get_str(&discovery.parameter).map(Address::from_str);
+        let block_number = doc.get_i64("block_number");
+        if let (Ok(Ok(address)), Ok(block_number)) = (address, block_number) {
+            children.push((address, block_number as u64));
+        }
+    }
+    Ok(children)
+}
+
+/// The id of the checkpoint of a mapping, prefixed with the chain id
+/// so the sources of several chains can share a database.
+fn checkpoint_key(chain_id: u64, mapping: &IndexerContractMapping) -> String {
+    format!("{}:{}", chain_id, mapping.checkpoint_id())
+}
+
+/// Removes the stored checkpoints of the given mappings, so the next sync starts from scratch.
+pub async fn reset_checkpoints(
+    db: &Database,
+    chain_id: u64,
+    event_mappings: &[IndexerContractMapping],
+) -> eyre::Result<()> {
+    let ids: Vec<String> = event_mappings
+        .iter()
+        .map(|mapping| checkpoint_key(chain_id, mapping))
+        .collect();
+
+    db.collection::<Document>(CHECKPOINTS_COLLECTION)
+        .delete_many(doc! { "_id": { "$in": ids } })
+        .await?;
+    Ok(())
+}
+
+/// Marks `block_number` as fully processed for the given mappings.
+/// When a session is passed, the update becomes part of its running transaction.
+pub async fn update_checkpoints(
+    db: &Database,
+    mut session: Option<&mut ClientSession>,
+    chain_id: u64,
+    event_mappings: &[&IndexerContractMapping],
+    block_number: u64,
+) -> eyre::Result<()> {
+    let collection: Collection<Document> = db.collection(CHECKPOINTS_COLLECTION);
+    let update = doc! {
+        "$set": { "block_number": block_number as i64, "chain_id": chain_id as i64 }
+    };
+
+    for mapping in event_mappings {
+        let action = collection
+            .update_one(
+                doc! { "_id": checkpoint_key(chain_id, mapping) },
+                update.clone(),
+            )
+            .upsert(true);
+
+        match session.as_deref_mut() {
+            Some(session) => action.session(session).await?,
+            None => action.await?,
+        };
+    }
+    Ok(())
+}
+
+/// Rolls back a chain reorganization: deletes every document indexed from `fork_block` onwards,
+/// rewinds the checkpoints to the block before it and records the reorg, all in one transaction.
+/// `orphaned_blocks` holds the number, the indexed hash and the new canonical hash of each block.
+/// Returns the number of deleted documents.
+pub async fn rollback_reorg(
+    db: &Database,
+    session: &mut ClientSession,
+    chain_id: u64,
+    event_mappings: &[IndexerContractMapping],
+    fork_block: u64,
+    orphaned_blocks: &[(u64, B256, Option<B256>)],
+) -> eyre::Result<u64> {
+    session.start_transaction().await?;
+
+    let collection_names = event_mappings
+        .iter()
+        .flat_map(|mapping| mapping.collection_names())
+        .chain([DEAD_LETTERS_COLLECTION]);
+
+    let mut deleted = 0;
+    for collection_name in collection_names {
+        deleted += db
+            .collection::<Document>(collection_name)
+            .delete_many(doc! {
+                "block_number": { "$gte": fork_block as i64 },
+                "chain_id": chain_id as i64,
+            })
+            .session(&mut *session)
+            .await?
+            .deleted_count;
+    }
+
+    let ids: Vec<String> = event_mappings
+        .iter()
+        .map(|mapping| checkpoint_key(chain_id, mapping))
+        .collect();
+    let checkpoints = db.collection::<Document>(CHECKPOINTS_COLLECTION);
+    let filter = doc! { "_id": { "$in": ids }, "block_number": { "$gte": fork_block as i64 } };
+    // A reorg back to genesis leaves no indexed block to rewind the checkpoints to
+    match fork_block.checked_sub(1) {
+        Some(block_number) => {
+            checkpoints
+                .update_many(
+                    filter,
+                    doc! { "$set": { "block_number": block_number as i64 } },
+                )
+                .session(&mut *session)
+                .await?;
+        }
+        None => {
+            checkpoints
+                .delete_many(filter)
+                .session(&mut *session)
+                .await?;
+        }
+    }
+
+    let orphaned_blocks: Vec<Document> = orphaned_blocks
+        .iter()
+        .map(|(block_number, block_hash, canonical_hash)| {
+            doc! {
+                "block_number": *block_number as i64,
+                "block_hash": block_hash.to_string(),
+                "canonical_hash": canonical_hash.map(|hash| hash.to_string()),
+            }
+        })
+        .collect();
+    db.collection::<Document>(REORGS_COLLECTION)
+        .insert_one(doc! {
+            "detected_at": DateTime::now(),
+            "chain_id": chain_id as i64,
+            "fork_block": fork_block as i64,
+            "deleted_documents": deleted as i64,
+            "orphaned_blocks": orphaned_blocks,
+        })
+        .session(&mut *session)
+        .await?;
+
+    session.commit_transaction().await?;
+    Ok(deleted)
+}
+
+/// The transaction that emitted a batch of logs, with the fields requested by `includeTxFields`.
+#[derive(Debug, Default)]
+pub struct TxContext {
+    pub tx_index: u64,
+    /// Added to every document of the transaction, keyed by `TxField::key`.
+    pub fields: Document,
+    /// The block log index of the first log of the transaction, set if `tx_log_index` is included.
+    pub first_log_index: Option<u64>,
+}
+
+/// Collects the `includeTxFields` of a transaction. The sender is only recovered if requested.
+pub fn tx_context(
+    include_tx_fields: &[TxField],
     header: &Header,
     tx: &TransactionSignedNoHash,
-    logs: &[DecodedLog],
-) -> eyre::Result<()> {
-    let collection: Collection<Document> = db.collection(collection_name);
+    tx_index: u64,
+    first_log_index: u64,
+    receipt: &Receipt,
+    gas_used: u64,
+) -> TxContext {
+    let mut fields = Document::new();
+    for field in include_tx_fields {
+        let value: Bson = match field {
+            TxField::From => {
+                let from = tx.recover_signer();
+                if from.is_none() {
+                    log::warn!(
+                        "Could not recover the sender of tx {} in block {}",
+                        tx.hash(),
+                        header.number
+                    );
+                }
+                from.map(|from| from.to_checksum(None)).into()
+            }
+            TxField::To => tx
+                .transaction
+                .kind()
+                .to()
+                .map(|to| to.to_checksum(None))
+                .into(),
+            TxField::Nonce => (tx.transaction.nonce() as i64).into(),
+            TxField::GasPrice => {
+                let gas_price = tx.transaction.max_fee_per_gas();
+                numeric_to_bson(gas_price.to_string(), 128)
+            }
+            TxField::EffectiveGasPrice => {
+                let max_fee = tx.transaction.max_fee_per_gas();
+                let effective_gas_price = match (
+                    tx.transaction.max_priority_fee_per_gas(),
+                    header.base_fee_per_gas,
+                ) {
+                    (Some(priority_fee), Some(base_fee)) => {
+                        max_fee.min(base_fee as u128 + priority_fee)
+                    }
+                    _ => max_fee,
+                };
+                numeric_to_bson(effective_gas_price.to_string(), 128)
+            }
+            TxField::GasUsed => (gas_used as i64).into(),
+            TxField::Status => receipt.success.into(),
+            // Differs between the logs of the transaction, added by `log_documents`
+            TxField::TxLogIndex => continue,
+        };
+        fields.insert(field.key(), value);
+    }
 
-    let block_hash = header.hash_slow().to_string();
+    TxContext {
+        tx_index,
+        fields,
+        first_log_index: include_tx_fields
+            .contains(&TxField::TxLogIndex)
+            .then_some(first_log_index),
+    }
+}
+
+/// Builds the documents of the decoded logs of a transaction.
+pub fn log_documents(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    context: &TxContext,
+    logs: &[DecodedLog],
+) -> Vec<Document> {
+    let tx_hash = tx.hash().to_string();
+    let block_hash = block_hash.to_string();
     let block_number = match bson::to_bson(&(header.number as i64)) {
         Ok(block_number) => block_number,
         Err(_) => bson::to_bson(&header.number.to_string()).unwrap(),
     };
 
-    let docs: Vec<Document> = logs
-        .iter()
+    logs.iter()
         .map(|log| {
             let timestamp = DateTime::from_millis((header.timestamp as i64) * 1000);
             let mut doc = doc! {
                 "block_number": block_number.clone(),
                 "contract_address": log.address.to_string(),
-                "tx_hash": tx.hash().to_string(),
+                "tx_hash": &tx_hash,
+                "tx_index": context.tx_index as i64,
+                "log_index": log.log_index as i64,
                 "block_hash": block_hash.clone(),
                 "timestamp": timestamp,
             };
-
-            for topic in &log.topics {
-                doc.insert(&topic.name, &topic.value);
+            if let Some(first_log_index) = context.first_log_index {
+                let tx_log_index = (log.log_index - first_log_index) as i64;
+                doc.insert(TxField::TxLogIndex.key(), tx_log_index);
             }
+            let values = log
+                .topics
+                .iter()
+                .map(|topic| (topic.name.clone(), topic.value.clone()));
+            extend_decoded(&mut doc, context, values);
 
             doc
         })
+        .collect()
+}
+
+/// Builds the document of a decoded function call.
+pub fn call_document(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    context: &TxContext,
+    abi_item: &ABIItem,
+    status: bool,
+    arguments: Vec<DecodedTopic>,
+) -> Document {
+    let value = tx.transaction.value();
+    let mut doc = doc! {
+        "block_number": header.number as i64,
+        "contract_address": tx.transaction.kind().to().map(|to| to.to_string()),
+        "tx_hash": tx.hash().to_string(),
+        "tx_index": context.tx_index as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "function": &abi_item.name,
+        "value": numeric_to_bson(value.to_string(), value.bit_len()),
+        "status": status,
+    };
+    let values = arguments
+        .into_iter()
+        .map(|argument| (argument.name, argument.value));
+    extend_decoded(&mut doc, context, values);
+
+    doc
+}
+
+/// Adds the `includeTxFields` of the transaction and the decoded values to a document.
+/// The transaction fields have their own keys, so they are kept next to inputs of the same name.
+fn extend_decoded(
+    doc: &mut Document,
+    context: &TxContext,
+    values: impl IntoIterator<Item = (String, Bson)>,
+) {
+    doc.extend(context.fields.clone());
+    for (name, value) in values {
+        doc.insert(name, value);
+    }
+}
+
+/// Builds the document of a contract deployed by a transaction.
+pub fn deployment_document(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    tx_index: u64,
+    deployer: Address,
+    contract_address: Address,
+) -> Document {
+    doc! {
+        "block_number": header.number as i64,
+        "tx_hash": tx.hash().to_string(),
+        "tx_index": tx_index as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "contract_address": contract_address.to_checksum(None),
+        "deployer": deployer.to_checksum(None),
+        "init_code_hash": keccak256(tx.transaction.input()).to_string(),
+    }
+}
+
+/// Builds the document of a change of the balance or nonce of an account within a block.
+/// Accounts that did not exist before or do not exist anymore have `null` values.
+pub fn account_change_document(
+    header: &Header,
+    block_hash: B256,
+    address: Address,
+    before: Option<Account>,
+    after: Option<Account>,
+) -> Document {
+    let balance = |account: Option<Account>| {
+        account
+            .map(|account| numeric_to_bson(account.balance.to_string(), account.balance.bit_len()))
+    };
+    let nonce = |account: Option<Account>| account.map(|account| account.nonce as i64);
+
+    doc! {
+        "block_number": header.number as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "kind": "account",
+        "address": address.to_checksum(None),
+        "balance_before": balance(before),
+        "balance_after": balance(after),
+        "nonce_before": nonce(before),
+        "nonce_after": nonce(after),
+    }
+}
+
+/// Builds the document of a change of a storage slot within a block.
+pub fn storage_change_document(
+    header: &Header,
+    block_hash: B256,
+    address: Address,
+    slot: B256,
+    before: U256,
+    after: U256,
+) -> Document {
+    doc! {
+        "block_number": header.number as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "kind": "storage",
+        "address": address.to_checksum(None),
+        "slot": slot.to_string(),
+        "value_before": B256::from(before).to_string(),
+        "value_after": B256::from(after).to_string(),
+    }
+}
+
+/// Builds the document of a transaction that sends ETH.
+pub fn native_transfer_document(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    tx_index: u64,
+    from: Address,
+    receipt: &Receipt,
+    gas_used: u64,
+) -> Document {
+    let value = tx.transaction.value();
+    doc! {
+        "block_number": header.number as i64,
+        "tx_hash": tx.hash().to_string(),
+        "tx_index": tx_index as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "from": from.to_checksum(None),
+        "to": tx.transaction.kind().to().map(|to| to.to_checksum(None)),
+        "value": numeric_to_bson(value.to_string(), value.bit_len()),
+        "gas_used": gas_used as i64,
+        "status": receipt.success,
+    }
+}
+
+/// Builds the dead-letter documents of the logs of a transaction that could not be decoded.
+pub fn dead_letter_documents(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    context: &TxContext,
+    abi_item: &ABIItem,
+    failures: &[DecodeFailure],
+) -> Vec<Document> {
+    let tx_hash = tx.hash().to_string();
+    let block_hash = block_hash.to_string();
+
+    failures
+        .iter()
+        .map(|failure| {
+            let topics: Vec<String> = failure
+                .log
+                .topics()
+                .iter()
+                .map(|topic| topic.to_string())
+                .collect();
+
+            doc! {
+                "block_number": header.number as i64,
+                "contract_address": failure.log.address.to_string(),
+                "tx_hash": &tx_hash,
+                "tx_index": context.tx_index as i64,
+                "log_index": failure.log_index as i64,
+                "block_hash": &block_hash,
+                "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+                "abi_item": &abi_item.name,
+                "collection_name": &abi_item.collection_name,
+                "topics": topics,
+                "data": failure.log.data.data.to_string(),
+                "error": failure.error.to_string(),
+            }
+        })
+        .collect()
+}
+
+/// Builds the dead-letter document of a function call that could not be decoded.
+pub fn dead_letter_call_document(
+    header: &Header,
+    block_hash: B256,
+    tx: &TransactionSignedNoHash,
+    context: &TxContext,
+    abi_item: &ABIItem,
+    error: &DecodeError,
+) -> Document {
+    doc! {
+        "block_number": header.number as i64,
+        "contract_address": tx.transaction.kind().to().map(|to| to.to_string()),
+        "tx_hash": tx.hash().to_string(),
+        "tx_index": context.tx_index as i64,
+        "block_hash": block_hash.to_string(),
+        "timestamp": DateTime::from_millis((header.timestamp as i64) * 1000),
+        "abi_item": &abi_item.name,
+        "collection_name": &abi_item.collection_name,
+        "input": tx.transaction.input().to_string(),
+        "error": error.to_string(),
+    }
+}
+
+/// Inserts a batch of documents into a collection, stamped with the `chain_id` they belong to.
+/// Documents whose `key_fields` (the fields of the collection's unique index) are already
+/// stored are left out, so re-running a range never produces duplicates or duplicate key errors.
+pub async fn insert_documents(
+    db: &Database,
+    session: &mut ClientSession,
+    chain_id: u64,
+    collection_name: &str,
+    key_fields: &[&str],
+    mut docs: Vec<Document>,
+) -> eyre::Result<()> {
+    let collection: Collection<Document> = db.collection(collection_name);
+    for doc in &mut docs {
+        doc.insert("chain_id", chain_id as i64);
+    }
+
+    // Missing fields are indexed as null
+    let document_key = |doc: &Document| -> Vec<String> {
+        key_fields
+            .iter()
+            .map(|field| doc.get(field).unwrap_or(&Bson::Null).to_string())
+            .collect()
+    };
+
+    let block_numbers: HashSet<i64> = docs
+        .iter()
+        .filter_map(|doc| doc.get_i64("block_number").ok())
+        .collect();
+    let mut projection = doc! { "_id": 0 };
+    for field in key_fields {
+        projection.insert(*field, 1);
+    }
+    let mut cursor = collection
+        .find(doc! {
+            "block_number": { "$in": block_numbers.into_iter().collect::<Vec<_>>() },
+            "chain_id": chain_id as i64,
+        })
+        .projection(projection)
+        .session(&mut *session)
+        .await?;
+    let mut existing = HashSet::new();
+    while let Some(doc) = cursor.next(&mut *session).await.transpose()? {
+        existing.insert(document_key(&doc));
+    }
+
+    // Also drops duplicates within the batch, any duplicate key error would abort the transaction
+    let docs: Vec<Document> = docs
+        .into_iter()
+        .filter(|doc| existing.insert(document_key(doc)))
         .collect();
+    if docs.is_empty() {
+        return Ok(());
+    }
 
-    collection.insert_many(docs).await?;
+    collection
+        .insert_many(docs)
+        .ordered(false)
+        .session(session)
+        .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tx_fields_do_not_overwrite_decoded_values() {
+        let include_tx_fields: Vec<TxField> =
+            serde_json::from_str(r#"["from", "to", "status", "gas_used"]"#).unwrap();
+        let mut fields = Document::new();
+        for field in &include_tx_fields {
+            fields.insert(field.key(), format!("tx {:?}", field));
+        }
+        let context = TxContext {
+            fields,
+            ..Default::default()
+        };
+
+        let mut doc = doc! { "block_number": 1_i64 };
+        let values = [
+            ("to".to_string(), Bson::from("recipient")),
+            ("status".to_string(), Bson::from("pending")),
+        ];
+        extend_decoded(&mut doc, &context, values);
+
+        assert_eq!(doc.get_str("to").unwrap(), "recipient");
+        assert_eq!(doc.get_str("status").unwrap(), "pending");
+        assert_eq!(doc.get_str("tx_to").unwrap(), "tx To");
+        assert_eq!(doc.get_str("tx_status").unwrap(), "tx Status");
+        assert_eq!(doc.get_str("tx_from").unwrap(), "tx From");
+        assert_eq!(doc.get_str("tx_gas_used").unwrap(), "tx GasUsed");
+    }
+}
@@ -0,0 +1,120 @@
+use alloy::primitives::B256;
+use reth_provider::BlockHashReader;
+use std::collections::VecDeque;
+
+/// Window of the most recently indexed blocks and their hashes.
+/// Used to detect when a block that was already indexed is no longer canonical.
+#[derive(Debug)]
+pub struct BlockWindow {
+    blocks: VecDeque<(u64, B256)>,
+    capacity: usize,
+}
+
+impl BlockWindow {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            blocks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Records an indexed block, evicting the lowest one if the window is full.
+    /// Blocks may be pushed in any order, the window is kept sorted by block number.
+    /// A block that is pushed again replaces its previous hash.
+    pub fn push(&mut self, block_number: u64, block_hash: B256) {
+        if self.capacity == 0 {
+            return;
+        }
+        let position = self
+            .blocks
+            .partition_point(|(number, _)| *number < block_number);
+        if let Some((number, hash)) = self.blocks.get_mut(position) {
+            if *number == block_number {
+                *hash = block_hash;
+                return;
+            }
+        }
+        self.blocks.insert(position, (block_number, block_hash));
+        if self.blocks.len() > self.capacity {
+            self.blocks.pop_front();
+        }
+    }
+
+    /// Returns the lowest indexed block whose hash no longer matches the canonical chain.
+    /// Reorgs deeper than the window can not be detected.
+    pub fn find_reorg<T: BlockHashReader>(&self, provider: &T) -> eyre::Result<Option<u64>> {
+        for (block_number, block_hash) in &self.blocks {
+            if provider.block_hash(*block_number)? != Some(*block_hash) {
+                return Ok(Some(*block_number));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Removes and returns every block from `block_number` onwards.
+    pub fn split_off(&mut self, block_number: u64) -> Vec<(u64, B256)> {
+        let position = self
+            .blocks
+            .iter()
+            .position(|(number, _)| *number >= block_number)
+            .unwrap_or(self.blocks.len());
+        self.blocks.split_off(position).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(window: &BlockWindow) -> Vec<u64> {
+        window.blocks.iter().map(|(number, _)| *number).collect()
+    }
+
+    #[test]
+    fn push_keeps_blocks_sorted() {
+        let mut window = BlockWindow::new(4);
+        for block_number in [12, 10, 13, 11] {
+            window.push(block_number, B256::with_last_byte(block_number as u8));
+        }
+        assert_eq!(numbers(&window), vec![10, 11, 12, 13]);
+    }
+
+    #[test]
+    fn push_evicts_the_lowest_block() {
+        let mut window = BlockWindow::new(2);
+        for block_number in [11, 12, 10] {
+            window.push(block_number, B256::ZERO);
+        }
+        assert_eq!(numbers(&window), vec![11, 12]);
+    }
+
+    #[test]
+    fn push_replaces_a_duplicate_block() {
+        let mut window = BlockWindow::new(4);
+        window.push(10, B256::with_last_byte(1));
+        window.push(11, B256::ZERO);
+        window.push(10, B256::with_last_byte(2));
+
+        assert_eq!(numbers(&window), vec![10, 11]);
+        assert_eq!(window.blocks[0].1, B256::with_last_byte(2));
+    }
+
+    #[test]
+    fn push_ignores_blocks_without_capacity() {
+        let mut window = BlockWindow::new(0);
+        window.push(10, B256::ZERO);
+        assert!(numbers(&window).is_empty());
+    }
+
+    #[test]
+    fn split_off_removes_blocks_from_the_fork() {
+        let mut window = BlockWindow::new(4);
+        for block_number in [10, 11, 12] {
+            window.push(block_number, B256::ZERO);
+        }
+
+        let orphaned = window.split_off(11);
+        assert_eq!(orphaned.len(), 2);
+        assert_eq!(numbers(&window), vec![10]);
+    }
+}
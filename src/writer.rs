@@ -0,0 +1,503 @@
+use alloy::primitives::B256;
+use log::info;
+use mongodb::{bson::Document, ClientSession, Database};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::IndexerContractMapping,
+    discovery::DiscoveredAddresses,
+    mongodb::{
+        insert_documents, load_discovered_addresses, rollback_reorg, unique_key_fields,
+        update_checkpoints, DEAD_LETTER_KEY_FIELDS,
+    },
+};
+
+/// How often the flush statistics are reported while blocks are written.
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The documents a single block contributes to MongoDB, grouped by collection.
+#[derive(Debug, Default)]
+pub struct BlockWrites {
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub documents: HashMap<String, Vec<Document>>,
+}
+
+impl BlockWrites {
+    pub fn new(block_number: u64, block_hash: B256) -> Self {
+        Self {
+            block_number,
+            block_hash,
+            documents: HashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, collection_name: &str, documents: Vec<Document>) {
+        self.documents
+            .entry(collection_name.to_string())
+            .or_default()
+            .extend(documents);
+    }
+}
+
+/// Statistics about the flushes done by a `Writer`, used to tune the flush thresholds.
+#[derive(Debug, Default)]
+pub struct FlushStats {
+    pub flushes: u64,
+    pub documents: u64,
+    pub largest_flush: usize,
+    pub duration: Duration,
+}
+
+impl fmt::Display for FlushStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let average_documents = self.documents.checked_div(self.flushes).unwrap_or(0);
+        let average_duration = self
+            .duration
+            .checked_div(self.flushes as u32)
+            .unwrap_or_default();
+        write!(
+            f,
+            "{} flushes, {} documents (avg {} / largest {} per flush), {:.2}s writing (avg {}ms per flush)",
+            self.flushes,
+            self.documents,
+            average_documents,
+            self.largest_flush,
+            self.duration.as_secs_f32(),
+            average_duration.as_millis()
+        )
+    }
+}
+
+/// Buffers processed blocks and writes them to MongoDB in batches.
+///
+/// The buffer is flushed once a collection holds `syncBackRoughlyEveryNLogs` documents
+/// or `flushIntervalMs` passed since the last flush. Every flush writes the documents and
+/// the checkpoint update in a single transaction.
+///
+/// Blocks may arrive in any order. The checkpoints only advance to the highest block
+/// below which every block has been received, so a resumed sync never leaves gaps.
+pub struct Writer<'a> {
+    db: Database,
+    session: ClientSession,
+    event_mappings: &'a [IndexerContractMapping],
+    progress: BlockProgress,
+    buffer: HashMap<String, Vec<Document>>,
+    /// Per collection number of documents that triggers a flush.
+    flush_thresholds: HashMap<String, usize>,
+    /// Per collection fields of the unique index, already stored documents are skipped by them.
+    key_fields: HashMap<String, &'static [&'static str]>,
+    flush_interval: Duration,
+    last_flush: Instant,
+    stats: FlushStats,
+    last_report: Instant,
+    /// Number of flushes when the statistics were last reported.
+    reported_flushes: u64,
+    discovered: Arc<DiscoveredAddresses>,
+    /// Stamped on every document and part of the checkpoint ids.
+    chain_id: u64,
+}
+
+impl<'a> Writer<'a> {
+    pub async fn new(
+        db: &Database,
+        event_mappings: &'a [IndexerContractMapping],
+        checkpoints: Vec<Option<u64>>,
+        start_block: u64,
+        flush_interval: Duration,
+        chain_id: u64,
+    ) -> eyre::Result<Self> {
+        let session = db.client().start_session().await?;
+
+        let flush_thresholds = event_mappings
+            .iter()
+            .flat_map(|mapping| {
+                mapping
+                    .collection_names()
+                    .into_iter()
+                    .map(|collection_name| {
+                        (collection_name.to_string(), mapping.sync_back_every_n_log)
+                    })
+            })
+            .collect();
+
+        let key_fields = event_mappings
+            .iter()
+            .flat_map(|mapping| {
+                mapping
+                    .collection_names()
+                    .into_iter()
+                    .map(|collection_name| {
+                        let key_fields = unique_key_fields(mapping, collection_name);
+                        (collection_name.to_string(), key_fields)
+                    })
+            })
+            .collect();
+
+        // The children are persisted as the factory events they were discovered from
+        let discovered = DiscoveredAddresses::new(event_mappings);
+        for discovery in discovered.discoveries() {
+            for (address, block_number) in
+                load_discovered_addresses(db, chain_id, &discovery).await?
+            {
+                discovered.insert(&discovery, address, block_number);
+            }
+        }
+
+        Ok(Self {
+            db: db.clone(),
+            session,
+            event_mappings,
+            progress: BlockProgress::new(checkpoints, start_block),
+            buffer: HashMap::new(),
+            flush_thresholds,
+            key_fields,
+            flush_interval,
+            last_flush: Instant::now(),
+            stats: FlushStats::default(),
+            last_report: Instant::now(),
+            reported_flushes: 0,
+            discovered: Arc::new(discovered),
+            chain_id,
+        })
+    }
+
+    /// The last fully processed block of every mapping, in the same order as the mappings.
+    pub fn checkpoints(&self) -> &[Option<u64>] {
+        &self.progress.checkpoints
+    }
+
+    pub fn stats(&self) -> &FlushStats {
+        &self.stats
+    }
+
+    /// Whether the statistics should be reported again: once every `STATS_INTERVAL`,
+    /// if anything was flushed since the last report.
+    pub fn stats_due(&mut self) -> bool {
+        if self.last_report.elapsed() < STATS_INTERVAL
+            || self.stats.flushes == self.reported_flushes
+        {
+            return false;
+        }
+        self.last_report = Instant::now();
+        self.reported_flushes = self.stats.flushes;
+        true
+    }
+
+    /// The contracts discovered from factory events so far.
+    pub fn discovered(&self) -> &Arc<DiscoveredAddresses> {
+        &self.discovered
+    }
+
+    /// Buffers the documents of a block and flushes the buffer if a threshold is reached.
+    pub async fn write(&mut self, block: BlockWrites) -> eyre::Result<()> {
+        if !self.progress.receive(block.block_number) {
+            // Already received, only possible when the same block is sent twice
+            return Ok(());
+        }
+
+        for (collection_name, documents) in block.documents {
+            self.buffer
+                .entry(collection_name)
+                .or_default()
+                .extend(documents);
+        }
+
+        if self.should_flush() {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the buffer if the flush interval passed, even if no new blocks arrived.
+    pub async fn flush_if_due(&mut self) -> eyre::Result<()> {
+        if self.last_flush.elapsed() >= self.flush_interval {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes every buffered document and the checkpoint of the last fully received block.
+    pub async fn flush(&mut self) -> eyre::Result<()> {
+        let checkpoint = self.progress.unpersisted_checkpoint();
+        self.last_flush = Instant::now();
+
+        if self.buffer.is_empty() {
+            // Nothing was written, so there is nothing the checkpoint has to be atomic with
+            if let Some(checkpoint) = checkpoint {
+                self.persist_checkpoints(checkpoint, false).await?;
+            }
+            return Ok(());
+        }
+
+        let started = Instant::now();
+        let buffer = std::mem::take(&mut self.buffer);
+        let documents: usize = buffer.values().map(Vec::len).sum();
+
+        self.session.start_transaction().await?;
+        for (collection_name, documents) in buffer {
+            // Every collection but the dead letters belongs to a mapping
+            let key_fields = self
+                .key_fields
+                .get(&collection_name)
+                .copied()
+                .unwrap_or(DEAD_LETTER_KEY_FIELDS);
+            insert_documents(
+                &self.db,
+                &mut self.session,
+                self.chain_id,
+                &collection_name,
+                key_fields,
+                documents,
+            )
+            .await?;
+        }
+        if let Some(checkpoint) = checkpoint {
+            self.persist_checkpoints(checkpoint, true).await?;
+        }
+        self.session.commit_transaction().await?;
+
+        let duration = started.elapsed();
+        self.stats.flushes += 1;
+        self.stats.documents += documents as u64;
+        self.stats.largest_flush = self.stats.largest_flush.max(documents);
+        self.stats.duration += duration;
+        info!(
+            "Flushed {} documents in {}ms (checkpoint: {:?})",
+            documents,
+            duration.as_millis(),
+            checkpoint
+        );
+
+        Ok(())
+    }
+
+    /// Deletes everything indexed from `fork_block` onwards and rewinds the checkpoints,
+    /// so the blocks can be indexed again. Returns the number of deleted documents.
+    pub async fn rollback(
+        &mut self,
+        fork_block: u64,
+        orphaned_blocks: &[(u64, B256, Option<B256>)],
+    ) -> eyre::Result<u64> {
+        // Buffered documents of orphaned blocks are deleted together with the stored ones
+        self.flush().await?;
+
+        let deleted = rollback_reorg(
+            &self.db,
+            &mut self.session,
+            self.chain_id,
+            self.event_mappings,
+            fork_block,
+            orphaned_blocks,
+        )
+        .await?;
+
+        self.progress.rollback(fork_block);
+        self.discovered.rollback(fork_block);
+
+        Ok(deleted)
+    }
+
+    fn should_flush(&self) -> bool {
+        self.last_flush.elapsed() >= self.flush_interval
+            || self.buffer.iter().any(|(collection_name, documents)| {
+                self.flush_thresholds
+                    .get(collection_name)
+                    .is_some_and(|threshold| documents.len() >= *threshold)
+            })
+    }
+
+    /// Stores `block_number` as the checkpoint of every mapping that is behind it.
+    async fn persist_checkpoints(
+        &mut self,
+        block_number: u64,
+        in_transaction: bool,
+    ) -> eyre::Result<()> {
+        let mappings: Vec<&IndexerContractMapping> = self
+            .event_mappings
+            .iter()
+            .zip(&self.progress.checkpoints)
+            // Includes mappings that were advanced in memory but not persisted yet
+            .filter(|(_, checkpoint)| {
+                checkpoint.is_none_or(|checkpoint| checkpoint <= block_number)
+            })
+            .map(|(mapping, _)| mapping)
+            .collect();
+
+        let session = in_transaction.then_some(&mut self.session);
+        update_checkpoints(&self.db, session, self.chain_id, &mappings, block_number).await?;
+        self.progress.persisted_block = Some(block_number);
+        Ok(())
+    }
+}
+
+/// Tracks the received blocks, so the checkpoints only advance to the highest block
+/// below which every block has been received.
+#[derive(Debug)]
+struct BlockProgress {
+    /// The last fully processed block of every mapping.
+    checkpoints: Vec<Option<u64>>,
+    /// The lowest block that has not been received yet.
+    next_block: u64,
+    /// Blocks above `next_block` that have already been received.
+    received_ahead: BTreeSet<u64>,
+    /// The last block whose checkpoint was stored in MongoDB.
+    persisted_block: Option<u64>,
+}
+
+impl BlockProgress {
+    fn new(checkpoints: Vec<Option<u64>>, start_block: u64) -> Self {
+        Self {
+            checkpoints,
+            next_block: start_block,
+            received_ahead: BTreeSet::new(),
+            persisted_block: None,
+        }
+    }
+
+    /// Records a received block and advances the checkpoints past the contiguous blocks.
+    /// Returns false if the block was already received.
+    fn receive(&mut self, block_number: u64) -> bool {
+        if block_number < self.next_block || !self.received_ahead.insert(block_number) {
+            return false;
+        }
+
+        let previous_next_block = self.next_block;
+        while self.received_ahead.remove(&self.next_block) {
+            self.next_block += 1;
+        }
+        if self.next_block > previous_next_block {
+            self.advance_checkpoints(self.next_block - 1);
+        }
+        true
+    }
+
+    /// The checkpoint of the last fully received block, if it was not stored yet.
+    fn unpersisted_checkpoint(&self) -> Option<u64> {
+        self.next_block
+            .checked_sub(1)
+            .filter(|checkpoint| self.persisted_block != Some(*checkpoint))
+    }
+
+    /// Rewinds everything to the block before `fork_block`.
+    fn rollback(&mut self, fork_block: u64) {
+        for checkpoint in self.checkpoints.iter_mut() {
+            if checkpoint.is_some_and(|checkpoint| checkpoint >= fork_block) {
+                *checkpoint = fork_block.checked_sub(1);
+            }
+        }
+        self.next_block = self.next_block.min(fork_block);
+        self.received_ahead.clear();
+        self.persisted_block = self
+            .persisted_block
+            .zip(fork_block.checked_sub(1))
+            .map(|(block, last_kept)| block.min(last_kept));
+    }
+
+    fn advance_checkpoints(&mut self, block_number: u64) {
+        for checkpoint in self.checkpoints.iter_mut() {
+            if checkpoint.is_none_or(|checkpoint| checkpoint < block_number) {
+                *checkpoint = Some(block_number);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoints_advance_past_contiguous_blocks_only() {
+        let mut progress = BlockProgress::new(vec![None, Some(5)], 10);
+
+        assert!(progress.receive(12));
+        assert!(progress.receive(11));
+        assert_eq!(progress.checkpoints, vec![None, Some(5)]);
+        assert_eq!(progress.unpersisted_checkpoint(), Some(9));
+
+        assert!(progress.receive(10));
+        assert_eq!(progress.next_block, 13);
+        assert!(progress.received_ahead.is_empty());
+        assert_eq!(progress.checkpoints, vec![Some(12), Some(12)]);
+        assert_eq!(progress.unpersisted_checkpoint(), Some(12));
+    }
+
+    #[test]
+    fn checkpoints_ahead_of_the_blocks_are_kept() {
+        let mut progress = BlockProgress::new(vec![Some(20), None], 10);
+
+        assert!(progress.receive(10));
+        assert_eq!(progress.checkpoints, vec![Some(20), Some(10)]);
+    }
+
+    #[test]
+    fn duplicate_blocks_are_ignored() {
+        let mut progress = BlockProgress::new(vec![None], 10);
+
+        assert!(progress.receive(10));
+        assert!(!progress.receive(10));
+        assert!(progress.receive(12));
+        assert!(!progress.receive(12));
+        assert_eq!(progress.next_block, 11);
+        assert_eq!(progress.checkpoints, vec![Some(10)]);
+    }
+
+    #[test]
+    fn persisted_checkpoint_is_not_stored_again() {
+        let mut progress = BlockProgress::new(vec![None], 10);
+
+        assert_eq!(progress.unpersisted_checkpoint(), Some(9));
+        progress.receive(10);
+        progress.persisted_block = Some(10);
+        assert_eq!(progress.unpersisted_checkpoint(), None);
+    }
+
+    #[test]
+    fn rollback_rewinds_to_the_fork() {
+        let mut progress = BlockProgress::new(vec![None, Some(3)], 10);
+        for block_number in [10, 11, 12, 14] {
+            progress.receive(block_number);
+        }
+        progress.persisted_block = Some(12);
+
+        progress.rollback(11);
+        assert_eq!(progress.next_block, 11);
+        assert!(progress.received_ahead.is_empty());
+        assert_eq!(progress.checkpoints, vec![Some(10), Some(10)]);
+        assert_eq!(progress.persisted_block, Some(10));
+        assert_eq!(progress.unpersisted_checkpoint(), None);
+
+        // The orphaned blocks are indexed again
+        assert!(progress.receive(11));
+        assert_eq!(progress.checkpoints, vec![Some(11), Some(11)]);
+    }
+
+    #[test]
+    fn rollback_keeps_checkpoints_before_the_fork() {
+        let mut progress = BlockProgress::new(vec![Some(3)], 4);
+        progress.receive(4);
+
+        progress.rollback(8);
+        assert_eq!(progress.next_block, 5);
+        assert_eq!(progress.checkpoints, vec![Some(4)]);
+    }
+
+    #[test]
+    fn rollback_to_genesis_clears_the_checkpoints() {
+        let mut progress = BlockProgress::new(vec![None, Some(3)], 0);
+        for block_number in [0, 1, 2, 3, 4] {
+            progress.receive(block_number);
+        }
+        progress.persisted_block = Some(4);
+
+        progress.rollback(0);
+        assert_eq!(progress.next_block, 0);
+        assert_eq!(progress.checkpoints, vec![None, None]);
+        assert_eq!(progress.persisted_block, None);
+        assert_eq!(progress.unpersisted_checkpoint(), None);
+    }
+}